@@ -1,14 +1,24 @@
 
 use super::config;
 use super::source::{Source, Snapshot, lvm, cephfs};
-use super::destination::{Destination, BackupSearchRequest, TargetDescriptor, TargetType, aws, fd, null};
-use super::encryption::{self, Cryptor};
-use super::compression::{self, Compressor};
+use super::source::filter::PathFilter;
+use super::destination::{Destination, BackupSearchRequest, Encoding, Target, TargetDescriptor, TargetType, aws, dir, fd, memory, null};
+use super::encryption::{self, Cryptor, EncryptionKind};
+use super::compression::{self, Compressor, CompressionKind};
 use super::manifest::{Entry, Manifest};
+use super::restore::{create_read_pipeline, resolve_chain};
+use super::sparse;
+use super::xattrs;
 
+use std::collections::{HashMap, VecDeque};
 use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
 use std::str::FromStr;
+use std::sync;
 
 use tar;
 
@@ -20,6 +30,8 @@ use cron::Schedule;
 
 use gethostname::gethostname;
 
+use sha2::Digest;
+
 pub struct Job {
    pub name: String,
    pub typ: config::JobType,
@@ -27,6 +39,98 @@ pub struct Job {
    pub destination: config::Destination,
    pub compression: Option<config::Compression>,
    pub encryption: Option<config::Encryption>,
+   pub retention: Option<config::Retention>,
+   pub obfuscate_manifest: bool,
+   pub change_detection: config::ChangeDetection,
+   pub include: Vec<String>,
+   pub exclude: Vec<String>,
+   pub exclude_caches: bool,
+   pub exclude_markers: Vec<String>,
+   pub one_file_system: bool,
+   pub include_mounts: Vec<String>,
+   pub xattrs: bool,
+}
+
+impl Job {
+    pub fn verification_key_file(&self) -> Option<&str> {
+        match self.encryption.as_ref().map(|e| &e.typ) {
+            Some(config::EncryptionType::Pgp { verification_key_file: Some(ref f), .. }) => Some(f.as_str()),
+            _ => None,
+        }
+    }
+
+    /// The compression and encryption of backups written by this job.
+    pub fn encoding(&self) -> Encoding {
+        Encoding {
+            compression: self.compression.as_ref().map(|c| CompressionKind::of(&c.typ)),
+            encryption: self.encryption.as_ref().map(|e| EncryptionKind::of(&e.typ)),
+        }
+    }
+}
+
+pub fn load_job(config: &config::Config, name: &str) -> Result<Job, Error> {
+    let job = config.jobs.as_ref()
+        .ok_or_else(|| format_err!("no job configs found"))?
+        .iter()
+        .find(|j| j.name == name)
+        .ok_or_else(|| format_err!("backup job {} not found", name))?;
+
+    let src = config.sources.as_ref()
+        .ok_or_else(|| format_err!("no source configs found"))?
+        .iter()
+        .find(|s| s.name == job.source)
+        .ok_or_else(|| format_err!("source {} not found", job.source))?;
+
+    let dest = config.destinations.as_ref()
+        .ok_or_else(|| format_err!("no destination configs found"))?
+        .iter()
+        .find(|d| d.name == job.destination)
+        .ok_or_else(|| format_err!("destination {} not found", job.destination))?;
+
+    let comp = match job.compression {
+        None => None,
+        Some(ref comp) => {
+            let compression = config.compression.as_ref()
+                .ok_or_else(|| format_err!("no compression configs found"))?
+                .iter()
+                .find(|c| &c.name == comp)
+                .ok_or_else(|| format_err!("compression {} not found", comp))?;
+            Some(compression.clone())
+        }
+    };
+
+    let encr = match job.encryption {
+        None => None,
+        Some(ref enc) => {
+            let encryption = config.encryption.as_ref()
+                .ok_or_else(|| format_err!("no encryption configs found"))?
+                .iter()
+                .find(|e| &e.name == enc)
+                .ok_or_else(|| format_err!("encryption {} not found", enc))?;
+            Some(encryption.clone())
+        }
+    };
+
+    let job = Job {
+        name: job.name.clone(),
+        typ: job.typ.clone(),
+        source: src.clone(),
+        destination: dest.clone(),
+        encryption: encr,
+        compression: comp,
+        retention: job.retention.clone(),
+        obfuscate_manifest: job.obfuscate_manifest.unwrap_or(true),
+        change_detection: job.change_detection.unwrap_or(config::ChangeDetection::Metadata),
+        include: job.include.clone().unwrap_or_default(),
+        exclude: job.exclude.clone().unwrap_or_default(),
+        exclude_caches: job.exclude_caches.unwrap_or(false),
+        exclude_markers: job.exclude_markers.clone().unwrap_or_default(),
+        one_file_system: job.one_file_system.unwrap_or(false),
+        include_mounts: job.include_mounts.clone().unwrap_or_default(),
+        xattrs: job.xattrs.unwrap_or(false),
+    };
+
+    Ok(job)
 }
 
 pub fn backup(job: &Job) -> Result<(), Error> {
@@ -44,63 +148,66 @@ pub fn backup(job: &Job) -> Result<(), Error> {
     let hostname = gethostname().into_string()
         .map_err(|_| format_err!("failed to convert hostname to string"))?;
 
-    let destination = build_destination(job)?;
+    let destination = build_destination(&job.destination)?;
+
+    let request = BackupSearchRequest::new(hostname.as_str(), job.name.as_str());
 
-    let last_full_backup = match job.typ {
+    let base_backup = match job.typ {
         config::JobType::Full => None,
         config::JobType::Differential { ref full_backup_schedule } => {
-            let schedule = Schedule::from_str(&full_backup_schedule)
-                .map_err(|e| format_err!("failed to parse schedule: {}", e))?;
-
-            let request = BackupSearchRequest::new(hostname.as_str(), job.name.as_str());
-            let mut backups = destination.list_backups(&request)?
-                .into_iter()
-                .filter(|x| x.kind() == TargetType::Full)
-                .collect::<Vec<_>>();
-            
+            let mut backups = destination.list_backups(&request)?;
+            backups.sort_by(|a, b| a.timestamp().cmp(b.timestamp()));
+            current_full_backup(&backups, full_backup_schedule, &timestamp)?.cloned()
+        },
+        config::JobType::Incremental { ref full_backup_schedule, max_chain_length } => {
+            let mut backups = destination.list_backups(&request)?;
             backups.sort_by(|a, b| a.timestamp().cmp(b.timestamp()));
 
-            match backups.pop() {
-                None => {
-                    info!("no full backups found");
-                    None
-                },
-                Some(backup) => {
-                    let next_occurence = schedule.after(backup.timestamp()).nth(0).unwrap();
-
-                    if next_occurence > timestamp {
-                        info!("base backup is host = {}, job = {}, time = {}", backup.host(), backup.job(), backup.timestamp());
-                        Some(backup)
-                    } else {
-                        info!("last full backup was scheduled for {}", next_occurence);
-                        None
+            match current_full_backup(&backups, full_backup_schedule, &timestamp)? {
+                None => None,
+                Some(full) => {
+                    let latest = backups.iter()
+                        .filter(|b| b.timestamp() >= full.timestamp())
+                        .last()
+                        .unwrap_or(full);
+                    let chain = resolve_chain(job, destination.as_ref(), &backups, latest)?;
+                    let increments = chain.len() - 1;
+
+                    match max_chain_length {
+                        Some(max) if increments >= max => {
+                            info!("backup chain has {} increments, maximum is {}", increments, max);
+                            None
+                        },
+                        _ => {
+                            info!("base backup is host = {}, job = {}, time = {}", latest.host(), latest.job(), latest.timestamp());
+                            Some(latest.clone())
+                        },
                     }
                 },
             }
-        }
+        },
     };
 
-    let full_manifest = match last_full_backup {
+    let base_manifest = match base_backup {
         None => None,
-        Some(ref f) => {
-            let data = destination.fetch_manifest(&f)?;
-            let parsed = Manifest::deserialize(&data[..])?;
-            Some(parsed)
-        },
+        Some(ref f) => Some(fetch_manifest(destination.as_ref(), f, Some(job))?),
     };
 
-    let target_kind = match (&job.typ, &last_full_backup) {
+    let target_kind = match (&job.typ, &base_backup) {
+        (_, None) => TargetType::Full,
         (config::JobType::Full { .. }, _) => TargetType::Full,
-        (config::JobType::Differential { .. }, None) => TargetType::Full,
         (config::JobType::Differential { .. }, Some(_)) => TargetType::Differential,
+        (config::JobType::Incremental { .. }, Some(_)) => TargetType::Incremental,
     };
 
     match target_kind {
         TargetType::Full => info!("creating a full backup"),
         TargetType::Differential => info!("creating a differential backup"),
+        TargetType::Incremental => info!("creating an incremental backup"),
     };
 
-    let desc = TargetDescriptor::new(hostname, job.name.as_str(), timestamp, target_kind);
+    let desc = TargetDescriptor::new(hostname, job.name.as_str(), timestamp, target_kind)
+        .with_encoding(job.encoding());
 
     info!("creating snapshot of source disk");
     let snapshot = source.snapshot()?;
@@ -112,7 +219,7 @@ pub fn backup(job: &Job) -> Result<(), Error> {
         })
         .and_then(|(compressor, ctx)| {
             info!("copying data from snapshot to target");
-            upload_archive(snapshot.as_ref(), compressor, full_manifest.as_ref())
+            upload_archive(job, snapshot.as_ref(), compressor, base_manifest.as_ref())
         });
 
     debug!("tearing down snapshot");
@@ -120,7 +227,7 @@ pub fn backup(job: &Job) -> Result<(), Error> {
         error!("failed to tear down snaphsot: {}", e);
     }
 
-    let manifest = result.and_then(|(target, manifest)| {
+    let mut manifest = result.and_then(|(target, manifest)| {
         let target = target.finalize()?;
         let target = target.finalize()?;
         info!("upload succeeded, finalizing target");
@@ -128,8 +235,11 @@ pub fn backup(job: &Job) -> Result<(), Error> {
         Ok(manifest)
     })?;
 
-    let mut buffer = Vec::new();
-    manifest.serialize(&mut buffer)?;
+    if let Some(ref base) = base_backup {
+        manifest.set_parent(base);
+    }
+
+    let buffer = encode_manifest(job, &manifest)?;
     info!("uploading manifest, size = {}", buffer.len());
     destination.upload_manifest(&desc, &buffer[..])?;
     info!("manifest uploaded successfully");
@@ -137,9 +247,39 @@ pub fn backup(job: &Job) -> Result<(), Error> {
     Ok(())
 }
 
-fn build_destination(job: &Job) -> Result<Box<Destination>, Error> {
-    info!("using destination '{}'", &job.source.name);
-    let destination = match &job.destination.typ {
+/// Returns the latest full backup, unless the schedule says a new full
+/// backup is due. `backups` must be sorted oldest first.
+fn current_full_backup<'a>(
+    backups: &'a [TargetDescriptor],
+    full_backup_schedule: &str,
+    timestamp: &DateTime<Utc>)
+    -> Result<Option<&'a TargetDescriptor>, Error>
+{
+    let schedule = Schedule::from_str(full_backup_schedule)
+        .map_err(|e| format_err!("failed to parse schedule: {}", e))?;
+
+    match backups.iter().filter(|x| x.kind() == TargetType::Full).last() {
+        None => {
+            info!("no full backups found");
+            Ok(None)
+        },
+        Some(backup) => {
+            let next_occurence = schedule.after(backup.timestamp()).nth(0).unwrap();
+
+            if next_occurence > *timestamp {
+                info!("last full backup is host = {}, job = {}, time = {}", backup.host(), backup.job(), backup.timestamp());
+                Ok(Some(backup))
+            } else {
+                info!("last full backup was scheduled for {}", next_occurence);
+                Ok(None)
+            }
+        },
+    }
+}
+
+pub(crate) fn build_destination(dest: &config::Destination) -> Result<Box<Destination>, Error> {
+    info!("using destination '{}'", &dest.name);
+    let destination = match &dest.typ {
         config::DestinationType::S3 { region, bucket, prefix, access_key_id, secret_access_key } => {
             Box::new(aws::AwsBucket::new(
                 region.as_ref(), 
@@ -155,12 +295,93 @@ fn build_destination(job: &Job) -> Result<Box<Destination>, Error> {
                 .open(path)?;
             Box::new(fd::FileDescriptorDestination::new(file)) as Box<Destination>
         }
+        config::DestinationType::Directory { path } => {
+            Box::new(dir::DirectoryDestination::new(path)) as Box<Destination>
+        },
         config::DestinationType::Null => Box::new(null::NullDestination) as Box<Destination>,
     };
 
     Ok(destination)
 }
 
+/// Runs the serialized manifest through the job's compression and
+/// encryption, so it leaks no more than the backup data itself.
+fn encode_manifest(job: &Job, manifest: &Manifest) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    manifest.serialize(&mut data)?;
+
+    if job.compression.is_none() && job.encryption.is_none() {
+        return Ok(data);
+    }
+
+    debug!("encoding manifest with the job's pipeline");
+    let buffer = sync::Arc::new(sync::Mutex::new(Vec::new()));
+    let target = Box::new(memory::MemoryTarget::new(buffer.clone()));
+
+    let (mut compressor, _ctx) = build_pipeline(job, target)?;
+    compressor.write_all(&data)?;
+    compressor.finalize()?.finalize()?.finalize()?;
+
+    let mut locked = buffer.lock().expect("mutex lock poisoned");
+    Ok(mem::replace(&mut *locked, Vec::new()))
+}
+
+/// Downloads and parses a manifest. Manifests written through a compression
+/// or encryption pipeline can only be read if the job is given, plain text
+/// manifests are always readable.
+pub(crate) fn fetch_manifest(
+    destination: &dyn Destination,
+    desc: &TargetDescriptor,
+    job: Option<&Job>)
+    -> Result<Manifest, Error>
+{
+    let data = destination.fetch_manifest(desc)?;
+    let verification_key_file = job.and_then(|j| j.verification_key_file());
+
+    let plaintext = match desc.encoding() {
+        Some(encoding) => encoding.compression.is_none() && encoding.encryption.is_none(),
+        // plain text manifests start with the decimal algorithm number, which
+        // no compression or encryption format uses as its first byte
+        None => data.first().map_or(true, |b| b.is_ascii_digit()),
+    };
+
+    // only PGP encrypted manifests carry a signature, any other encoding
+    // could have been written by anyone with access to the destination
+    if verification_key_file.is_some() {
+        let signed = match desc.encoding() {
+            Some(encoding) => encoding.encryption == Some(EncryptionKind::Pgp),
+            None => !plaintext,
+        };
+        if !signed {
+            bail!("manifest of backup from {} is not signed", desc.timestamp());
+        }
+    }
+
+    let data = match job {
+        _ if plaintext => data,
+        Some(job) => {
+            decode_manifest(job, desc, data)
+                .context(format!("failed to decode manifest of backup from {}", desc.timestamp()))?
+        },
+        None => bail!("manifest of backup from {} is encoded, it can only be read with the job's config", desc.timestamp()),
+    };
+
+    let manifest = Manifest::deserialize(&data[..])
+        .context("failed to parse manifest")?;
+    Ok(manifest)
+}
+
+fn decode_manifest(job: &Job, desc: &TargetDescriptor, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let reader = Box::new(memory::MemoryReader::new(data));
+    let mut decompressor = create_read_pipeline(job, desc, reader)?;
+
+    let mut buffer = Vec::new();
+    decompressor.read_to_end(&mut buffer)?;
+    decompressor.finalize()?.finalize()?.finalize()?;
+
+    Ok(buffer)
+}
+
 fn create_pipeline(
     job: &Job, 
     dest: &dyn Destination, 
@@ -170,66 +391,273 @@ fn create_pipeline(
 {
     info!("allocating a target with size hint {} for backup data", size_hint);
     let target = dest.allocate(&desc, size_hint)?;
+    build_pipeline(job, target)
+}
 
+fn build_pipeline(
+    job: &Job,
+    target: Box<dyn Target>)
+    -> Result<(Box<dyn Compressor>, Option<encryption::pgp::PgpContext>), Error>
+{
     let mut pgp_ctx = None;
 
     let cryptor = match &job.encryption {
         None => Box::new(encryption::identity::IdentityCryptor::new(target)) as Box<dyn Cryptor>,
         Some(cfg) => match cfg.typ {
-            config::EncryptionType::Pgp { ref pubkey_file } => {
-                let ctx = encryption::pgp::PgpContext::new(pubkey_file)?;
+            config::EncryptionType::Pgp { ref pubkey_file, ref pubkey_files, ref signing_key_file, .. } => {
+                let key_files = pubkey_file.iter()
+                    .chain(pubkey_files.iter().flatten())
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let ctx = encryption::pgp::PgpContext::new(&key_files, signing_key_file.as_ref().map(|f| f.as_str()))?;
                 let pgp = encryption::pgp::PgpCryptor::new(target, &ctx)?;
                 pgp_ctx = Some(ctx);
                 Box::new(pgp) as Box<dyn Cryptor>
-            }
+            },
+            config::EncryptionType::Age { ref recipients, .. } => {
+                Box::new(encryption::age::AgeCryptor::new(target, recipients)?) as Box<dyn Cryptor>
+            },
+            config::EncryptionType::Symmetric { ref passphrase_file, ref key_file, .. } => {
+                let secret = encryption::symmetric::Secret::load(
+                    passphrase_file.as_ref().map(|f| f.as_str()),
+                    key_file.as_ref().map(|f| f.as_str()))?;
+                Box::new(encryption::symmetric::SymmetricCryptor::new(target, &secret)?) as Box<dyn Cryptor>
+            },
         }
     };
 
     let compressor = match &job.compression {
         None => Box::new(compression::identity::IdentityCompressor::new(cryptor)) as Box<dyn Compressor>,
         Some(cfg) => match cfg.typ {
-            config::CompressionType::Gzip => Box::new(compression::gzip::GzipCompressor::new(cryptor)) as Box<dyn Compressor>,
+            config::CompressionType::Gzip { level, threads } => {
+                let level = level.map_or(Ok(flate2::Compression::best()), compression::gzip::compression_level)?;
+                match threads {
+                    Some(t) if t > 1 => Box::new(compression::gzip::ParallelGzipCompressor::new(cryptor, level, t)) as Box<dyn Compressor>,
+                    _ => Box::new(compression::gzip::GzipCompressor::new(cryptor, level)) as Box<dyn Compressor>,
+                }
+            },
+            config::CompressionType::Zstd { level, threads, long_distance_matching } => {
+                let zstd = compression::zstd::ZstdCompressor::new(
+                    cryptor,
+                    level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL),
+                    threads.unwrap_or(0),
+                    long_distance_matching.unwrap_or(false))?;
+                Box::new(zstd) as Box<dyn Compressor>
+            },
+            config::CompressionType::Xz { level, threads } => {
+                let xz = compression::xz::XzCompressor::new(cryptor, level.unwrap_or(6), threads.unwrap_or(1))?;
+                Box::new(xz) as Box<dyn Compressor>
+            },
+            config::CompressionType::Lz4 { level } => {
+                let lz4 = compression::lz4::Lz4Compressor::new(cryptor, level.unwrap_or(0))?;
+                Box::new(lz4) as Box<dyn Compressor>
+            },
         }
     };
 
     Ok((compressor, pgp_ctx))
 }
 
+/// Counts the bytes written to the compressor, which gives the offset of
+/// each entry in the uncompressed tar stream.
+struct CountingWriter {
+    inner: Box<dyn Compressor>,
+    count: u64,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hashes file contents while tar reads them.
+struct HashingReader<R> {
+    inner: R,
+    hasher: sha2::Sha256,
+    size: u64,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.input(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
+/// Decides if an entry can be left out of a differential backup because it
+/// is unchanged since the base backup.
+fn is_unchanged(
+    detection: config::ChangeDetection,
+    base: &Manifest,
+    entry: &Entry,
+    rel_path: &Path,
+    full_path: &Path,
+    is_file: bool)
+    -> Result<bool, Error>
+{
+    let unchanged = match detection {
+        config::ChangeDetection::Metadata => base.contains(entry),
+        config::ChangeDetection::Strict | config::ChangeDetection::Content => base.contains_strict(entry),
+    };
+
+    if !unchanged || !is_file || detection != config::ChangeDetection::Content {
+        return Ok(unchanged);
+    }
+
+    let file = fs::File::open(full_path)
+        .context(format!("failed to open file '{}'", rel_path.display()))?;
+    let mut reader = HashingReader { inner: file, hasher: base.content_hasher(), size: 0 };
+    io::copy(&mut reader, &mut io::sink())
+        .context(format!("failed to hash file '{}'", rel_path.display()))?;
+
+    let matches = base.content_matches(rel_path, &reader.hasher.result()[..]);
+    if !matches {
+        debug!("contents of '{}' changed without a metadata change", rel_path.display());
+    }
+
+    Ok(matches)
+}
+
+/// Appends a hard link entry pointing at `target`, which must be earlier in
+/// the archive.
+fn append_hard_link<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    target: &Path,
+    metadata: &fs::Metadata)
+    -> Result<(), Error>
+{
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(metadata, tar::HeaderMode::Complete);
+    header.set_entry_type(tar::EntryType::Link);
+    header.set_size(0);
+
+    if header.set_link_name(target).is_err() {
+        // link names that don't fit the header go into a GNU long link entry
+        let name = target.as_os_str().as_bytes();
+        let mut long = tar::Header::new_gnu();
+        long.as_gnu_mut().unwrap().name[..13].clone_from_slice(b"././@LongLink");
+        long.set_mode(0o644);
+        long.set_entry_type(tar::EntryType::GNULongLink);
+        long.set_size(name.len() as u64 + 1);
+        long.set_cksum();
+        builder.append(&long, name.chain(io::repeat(0).take(1)))?;
+    }
+
+    builder.append_data(&mut header, path, io::empty())?;
+
+    Ok(())
+}
+
 fn upload_archive(
+    job: &Job,
     snapshot: &dyn Snapshot,
     target: Box<dyn Compressor>,
     filter: Option<&Manifest>)
     -> Result<(Box<dyn Compressor>, Manifest), Error>
 {
-    let mut manifest = Manifest::new()?;
+    let mut manifest = Manifest::new(job.obfuscate_manifest)?;
+    if let Some(m) = filter {
+        manifest.inherit_salt(m);
+    }
 
-    let mut builder = tar::Builder::new(target);
+    let mut builder = tar::Builder::new(CountingWriter { inner: target, count: 0 });
     builder.follow_symlinks(false);
     builder.mode(tar::HeaderMode::Complete);
 
-    let files = snapshot.files()?;
+    // first archived path of every inode with more than one link
+    let mut links = HashMap::new();
+
+    let path_filter = PathFilter::new(&job.include, &job.exclude)?
+        .exclude_caches(job.exclude_caches)
+        .exclude_markers(&job.exclude_markers);
+    let mut files = snapshot.files()?.with_filter(path_filter);
+    if job.one_file_system {
+        files = files.one_file_system(&job.include_mounts);
+    }
     let base_path = files.base_path();
 
+    // new links of a differential backup that wait for the rest of the walk
+    let mut deferred = VecDeque::new();
+    let mut walked = false;
+
     debug!("enumerating snapshot files");
-    for entry in files {
-        let (rel_path, metadata) = entry?;
+    loop {
+        let next = if walked { None } else { files.next() };
+        let (rel_path, metadata) = match next {
+            Some(entry) => entry?,
+            None => {
+                walked = true;
+                match deferred.pop_front() {
+                    Some(entry) => entry,
+                    None => break,
+                }
+            },
+        };
 
         let modified = metadata.modified()?;
         let uid = metadata.uid();
         let gid = metadata.gid();
         let mode = metadata.mode();
-        let entry_desc = Entry::new(&rel_path, modified, uid, gid, mode);
+        let mut entry_desc = Entry::new(&rel_path, modified, uid, gid, mode);
+        entry_desc.set_stat(metadata.size(), metadata.ino(), Utc.timestamp(metadata.ctime(), metadata.ctime_nsec() as u32));
+
+        let full_path = base_path.join(&rel_path);
+        let file_type = metadata.file_type();
 
         if let Some(m) = filter {
-            
-            if m.contains(&entry_desc) {
+            if is_unchanged(job.change_detection, m, &entry_desc, &rel_path, &full_path, file_type.is_file())? {
                 trace!("skipping file '{}'", rel_path.display());
+                manifest.carry(&entry_desc, m);
+
+                // the earlier backups of the chain restore unchanged files
+                // first, so new links can still point at them
+                if metadata.nlink() > 1 && file_type.is_file() {
+                    links.entry((metadata.dev(), metadata.ino())).or_insert_with(|| rel_path.clone());
+                }
                 continue;
             }
         }
 
-        let full_path = base_path.join(&rel_path);
-        let file_type = metadata.file_type();
+        // an unchanged file found later in the walk may still be the target
+        // of a new link, so those are only archived once the walk is done
+        let known = links.contains_key(&(metadata.dev(), metadata.ino()));
+        if filter.is_some() && !walked && !known && metadata.nlink() > 1 && file_type.is_file() {
+            deferred.push_back((rel_path, metadata));
+            continue;
+        }
+
+        entry_desc.set_offset(builder.get_ref().count);
+
+        let link_target = match metadata.nlink() {
+            n if n > 1 && file_type.is_file() => links.get(&(metadata.dev(), metadata.ino())).cloned(),
+            _ => None,
+        };
+
+        // hard links share the attributes of the entry they point to
+        let mut pax = Vec::new();
+        if job.xattrs && link_target.is_none() {
+            let attrs = xattrs::read(&full_path)?;
+            if !attrs.is_empty() {
+                trace!("appending {} extended attributes of '{}'", attrs.len(), rel_path.display());
+                pax = xattrs::pax_records(&attrs);
+            }
+        }
+
+        // sparse files may need records of their own in the same PAX header,
+        // so regular files append it themselves
+        if !pax.is_empty() && !file_type.is_file() {
+            xattrs::append_pax_header(&mut builder, &pax)?;
+        }
 
         if file_type.is_dir() {
             trace!("appending dir '{}' to archive", rel_path.display());
@@ -237,13 +665,54 @@ fn upload_archive(
             manifest.insert(&entry_desc);
         }
 
-        if file_type.is_file() {
+        if let Some(ref target) = link_target {
+            trace!("appending hard link '{}' to '{}' to archive", rel_path.display(), target.display());
+            append_hard_link(&mut builder, &rel_path, target, &metadata)
+                .context(format!("failed to append hard link '{}'", rel_path.display()))?;
+            entry_desc.set_link(target);
+            manifest.insert(&entry_desc);
+        } else if file_type.is_file() {
             trace!("appending file '{}' to archive", rel_path.display());
-            let mut file = fs::File::open(&full_path)
+            let file = fs::File::open(&full_path)
                 .context(format!("failed to open file '{}'", rel_path.display()))?;
-            builder.append_file(&rel_path, &mut file)
-                .context(format!("failed to append file '{}'", rel_path.display()))?;
+            let file_metadata = file.metadata()?;
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata_in_mode(&file_metadata, tar::HeaderMode::Complete);
+
+            // only files using fewer blocks than their size can have holes
+            let regions = if file_metadata.blocks() * 512 < file_metadata.len() {
+                sparse::data_regions(&file, file_metadata.len())?
+            } else {
+                None
+            };
+
+            let (size, digest) = match regions {
+                Some(regions) => {
+                    trace!("file '{}' is sparse with {} data regions", rel_path.display(), regions.len());
+                    let mut reader = sparse::SparseReader::new(file, regions, file_metadata.len(), manifest.content_hasher());
+                    sparse::append_sparse(&mut builder, &mut header, &rel_path, &mut reader, pax)
+                        .context(format!("failed to append file '{}'", rel_path.display()))?;
+                    reader.finish()
+                },
+                None => {
+                    if !pax.is_empty() {
+                        xattrs::append_pax_header(&mut builder, &pax)?;
+                    }
+                    let mut reader = HashingReader { inner: file, hasher: manifest.content_hasher(), size: 0 };
+                    builder.append_data(&mut header, &rel_path, &mut reader)
+                        .context(format!("failed to append file '{}'", rel_path.display()))?;
+                    let mut digest = [0; 32];
+                    digest.copy_from_slice(&reader.hasher.result()[..]);
+                    (reader.size, digest)
+                },
+            };
+
+            entry_desc.set_content(size, digest);
             manifest.insert(&entry_desc);
+
+            if metadata.nlink() > 1 {
+                links.insert((metadata.dev(), metadata.ino()), rel_path.clone());
+            }
         }
 
         if file_type.is_symlink() {
@@ -255,9 +724,102 @@ fn upload_archive(
 
     info!("processed {} files", manifest.len());
 
-    let target = builder.into_inner()?;
+    let (skipped_files, skipped_dirs) = files.skipped();
+    if skipped_files > 0 || skipped_dirs > 0 {
+        info!("filters skipped {} files and {} directories", skipped_files, skipped_dirs);
+    }
+
+    if let Some(m) = filter {
+        manifest.record_deletions(m);
+        info!("recorded {} deleted files", manifest.deleted_len());
+    }
+
+    let target = builder.into_inner()?.inner;
 
     Ok((target, manifest))
 }
 
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::source::Files;
 
+    use tempfile::TempDir;
+
+    struct DirectorySnapshot {
+        path: PathBuf,
+    }
+
+    impl Snapshot for DirectorySnapshot {
+        fn size_hint(&self) -> Result<u64, Error> {
+            Ok(0)
+        }
+
+        fn files<'a>(&'a self) -> Result<Files<'a>, Error> {
+            Files::new(&self.path)
+        }
+
+        fn destroy(self: Box<Self>) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn job() -> Job {
+        let config: config::Config = toml::from_str(r#"
+            [[jobs]]
+            name = "job"
+            type = "full"
+            source = "source"
+            destination = "null"
+
+            [[sources]]
+            name = "source"
+            type = "cephfs"
+            path = "/"
+
+            [[destinations]]
+            name = "null"
+            type = "null"
+        "#).unwrap();
+        load_job(&config, "job").unwrap()
+    }
+
+    fn archive(job: &Job, snapshot: &dyn Snapshot, base: Option<&Manifest>) -> (Vec<u8>, Manifest) {
+        let buffer = sync::Arc::new(sync::Mutex::new(Vec::new()));
+        let target = Box::new(memory::MemoryTarget::new(buffer.clone()));
+        let (compressor, _ctx) = build_pipeline(job, target).unwrap();
+
+        let (compressor, manifest) = upload_archive(job, snapshot, compressor, base).unwrap();
+        compressor.finalize().unwrap().finalize().unwrap().finalize().unwrap();
+
+        let mut locked = buffer.lock().unwrap();
+        (mem::replace(&mut *locked, Vec::new()), manifest)
+    }
+
+    #[test]
+    fn link_to_unchanged_file() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("a"), b"data").unwrap();
+        let snapshot = DirectorySnapshot { path: dir.path().to_path_buf() };
+        let job = job();
+
+        let (_, base) = archive(&job, &snapshot, None);
+        fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+        let (data, _) = archive(&job, &snapshot, Some(&base));
+
+        let mut archive = tar::Archive::new(&data[..]);
+        let entries = archive.entries().unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                let link = e.link_name().unwrap().map(|l| l.into_owned());
+                (e.path().unwrap().into_owned(), e.header().entry_type(), link)
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(entries, vec![(PathBuf::from("b"), tar::EntryType::Link, Some(PathBuf::from("a")))]);
+    }
+}
@@ -1,21 +1,42 @@
 
-use std::io;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::thread;
 
-use crate::encryption::Cryptor;
+use crate::encryption::{Cryptor, Decryptor};
 
 use anyhow::Error;
 
+use crossbeam::channel;
+
 use flate2::Compression;
 use flate2::write::GzEncoder;
+use flate2::read::MultiGzDecoder;
+
+/// Amount of input compressed into each gzip member in parallel mode.
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Highest gzip compression level.
+const MAX_LEVEL: u32 = 9;
+
+/// Checks a configured compression level, flate2 silently accepts any value.
+pub fn compression_level(level: u32) -> Result<Compression, Error> {
+    if level > MAX_LEVEL {
+        bail!("gzip compression level must be between 0 and {}, got {}", MAX_LEVEL, level);
+    }
+
+    Ok(Compression::new(level))
+}
 
 pub struct GzipCompressor {
     encoder: GzEncoder<Box<Cryptor>>,
 }
 
 impl GzipCompressor {
-    pub fn new(w: Box<Cryptor>) -> Self {
+    pub fn new(w: Box<Cryptor>, level: Compression) -> Self {
         GzipCompressor { 
-            encoder: GzEncoder::new(w, Compression::best())
+            encoder: GzEncoder::new(w, level)
         }
     }
 }
@@ -35,4 +56,225 @@ impl super::Compressor for GzipCompressor {
         let inner = self.encoder.finish()?;
         Ok(inner)
     }
-}
\ No newline at end of file
+}
+
+struct Block {
+    data: Vec<u8>,
+    result: channel::Sender<io::Result<Vec<u8>>>,
+}
+
+/// Splits the input into fixed size blocks and compresses each one into a
+/// separate gzip member on a pool of worker threads. The members are written
+/// in order, so the output is a regular multi-member gzip stream.
+pub struct ParallelGzipCompressor {
+    inner: Box<Cryptor>,
+    block: Vec<u8>,
+    blocks: usize,
+    sender: Option<channel::Sender<Block>>,
+    pending: VecDeque<channel::Receiver<io::Result<Vec<u8>>>>,
+    max_pending: usize,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ParallelGzipCompressor {
+    pub fn new(w: Box<Cryptor>, level: Compression, threads: usize) -> Self {
+        let (tx, rx) = channel::bounded::<Block>(threads);
+
+        debug!("allocating {} gzip compression threads", threads);
+        let handles = (0..threads).map(|_| {
+            let rx = rx.clone();
+            thread::spawn(move || {
+                for block in rx.iter() {
+                    let result = compress_block(&block.data, level);
+                    // the receiver is gone if the compressor was dropped early
+                    let _ = block.result.send(result);
+                }
+                trace!("gzip compression thread exiting");
+            })
+        })
+        .collect();
+
+        ParallelGzipCompressor {
+            inner: w,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            blocks: 0,
+            sender: Some(tx),
+            pending: VecDeque::new(),
+            max_pending: threads * 2,
+            threads: handles,
+        }
+    }
+
+    fn dispatch(&mut self) -> io::Result<()> {
+        let data = mem::replace(&mut self.block, Vec::with_capacity(BLOCK_SIZE));
+        let (tx, rx) = channel::bounded(1);
+
+        self.sender.as_ref()
+            .expect("compressor already finalized")
+            .send(Block { data: data, result: tx })
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "gzip compression threads exited"))?;
+        self.pending.push_back(rx);
+        self.blocks += 1;
+
+        while self.pending.len() > self.max_pending {
+            self.write_next()?;
+        }
+
+        Ok(())
+    }
+
+    fn write_next(&mut self) -> io::Result<()> {
+        if let Some(rx) = self.pending.pop_front() {
+            let member = rx.recv()
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "gzip compression thread failed"))??;
+            self.inner.write_all(&member)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            self.write_next()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn compress_block(data: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 2), level);
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+impl io::Write for ParallelGzipCompressor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len(), BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..len]);
+
+        if self.block.len() >= BLOCK_SIZE {
+            self.dispatch()?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.block.is_empty() {
+            self.dispatch()?;
+        }
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+impl super::Compressor for ParallelGzipCompressor {
+    fn finalize(mut self: Box<Self>) -> Result<Box<Cryptor>, Error> {
+        // an empty input still needs one member to be a valid gzip file
+        if !self.block.is_empty() || self.blocks == 0 {
+            self.dispatch()?;
+        }
+        self.write_pending()?;
+
+        drop(self.sender.take());
+        for thread in self.threads.drain(..) {
+            thread.join()
+                .map_err(|_| format_err!("gzip compression thread panicked"))?;
+        }
+
+        debug!("wrote {} gzip members", self.blocks);
+        Ok(self.inner)
+    }
+}
+
+pub struct GzipDecompressor {
+    decoder: MultiGzDecoder<Box<Decryptor>>,
+}
+
+impl GzipDecompressor {
+    pub fn new(r: Box<Decryptor>) -> Self {
+        GzipDecompressor {
+            decoder: MultiGzDecoder::new(r)
+        }
+    }
+}
+
+impl io::Read for GzipDecompressor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buf)
+    }
+}
+
+impl super::Decompressor for GzipDecompressor {
+    fn finalize(mut self: Box<Self>) -> Result<Box<Decryptor>, Error> {
+        // tar stops reading at the end-of-archive marker, drain the rest of
+        // the stream so the gzip trailer gets checked
+        io::copy(&mut self.decoder, &mut io::sink())?;
+        Ok(self.decoder.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    use crate::compression::{Compressor, Decompressor};
+    use crate::destination::{Destination, TargetDescriptor, TargetType};
+    use crate::destination::dir::DirectoryDestination;
+    use crate::encryption::identity::{IdentityCryptor, IdentityDecryptor};
+
+    use chrono::prelude::*;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn levels_are_checked() {
+        assert_eq!(compression_level(0).unwrap(), Compression::none());
+        assert_eq!(compression_level(9).unwrap(), Compression::best());
+        assert!(compression_level(10).is_err());
+    }
+
+    #[test]
+    fn parallel_output_is_multi_member_gzip() {
+        let dir = TempDir::new().unwrap();
+        let destination = DirectoryDestination::new(dir.path());
+        let desc = TargetDescriptor::new("host", "job", Utc.timestamp(1500000000, 0), TargetType::Full);
+
+        let data = (0..3 * BLOCK_SIZE + 17).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        let target = destination.allocate(&desc, 0).unwrap();
+        let cryptor = Box::new(IdentityCryptor::new(target));
+        let mut compressor = Box::new(ParallelGzipCompressor::new(cryptor, Compression::fast(), 3));
+        compressor.write_all(&data).unwrap();
+        compressor.finalize().unwrap().finalize().unwrap().finalize().unwrap();
+
+        let reader = destination.open(&desc).unwrap();
+        let mut decompressor = Box::new(GzipDecompressor::new(Box::new(IdentityDecryptor::new(reader))));
+        let mut result = Vec::new();
+        decompressor.read_to_end(&mut result).unwrap();
+        decompressor.finalize().unwrap();
+
+        assert_eq!(result, data);
+    }
+
+    #[test]
+    fn parallel_empty_input_is_valid_gzip() {
+        let dir = TempDir::new().unwrap();
+        let destination = DirectoryDestination::new(dir.path());
+        let desc = TargetDescriptor::new("host", "job", Utc.timestamp(1500000000, 0), TargetType::Full);
+
+        let target = destination.allocate(&desc, 0).unwrap();
+        let cryptor = Box::new(IdentityCryptor::new(target));
+        let compressor = Box::new(ParallelGzipCompressor::new(cryptor, Compression::fast(), 2));
+        compressor.finalize().unwrap().finalize().unwrap().finalize().unwrap();
+
+        let reader = destination.open(&desc).unwrap();
+        let mut decompressor = GzipDecompressor::new(Box::new(IdentityDecryptor::new(reader)));
+        let mut result = Vec::new();
+        decompressor.read_to_end(&mut result).unwrap();
+
+        assert!(result.is_empty());
+    }
+}
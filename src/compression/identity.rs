@@ -1,7 +1,7 @@
 
 use std::io;
 
-use crate::encryption::Cryptor;
+use crate::encryption::{Cryptor, Decryptor};
 
 use anyhow::Error;
 
@@ -31,3 +31,25 @@ impl super::Compressor for IdentityCompressor {
     }
 }
 
+
+pub struct IdentityDecompressor {
+    inner: Box<Decryptor>,
+}
+
+impl IdentityDecompressor {
+    pub fn new(r: Box<Decryptor>) -> Self {
+        IdentityDecompressor { inner: r }
+    }
+}
+
+impl io::Read for IdentityDecompressor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl super::Decompressor for IdentityDecompressor {
+    fn finalize(self: Box<Self>) -> Result<Box<Decryptor>, Error> {
+        Ok(self.inner)
+    }
+}
@@ -0,0 +1,77 @@
+
+use std::io;
+
+use crate::encryption::{Cryptor, Decryptor};
+
+use anyhow::Error;
+
+use lz4::{ContentChecksum, Decoder, Encoder, EncoderBuilder};
+
+/// Highest lz4 level. Level 0 is the fast mode, levels from 3 up use the
+/// high compression mode.
+const MAX_LEVEL: u32 = 12;
+
+pub struct Lz4Compressor {
+    encoder: Encoder<Box<Cryptor>>,
+}
+
+impl Lz4Compressor {
+    pub fn new(w: Box<Cryptor>, level: u32) -> Result<Self, Error> {
+        if level > MAX_LEVEL {
+            bail!("lz4 compression level must be between 0 and {}, got {}", MAX_LEVEL, level);
+        }
+
+        let encoder = EncoderBuilder::new()
+            .level(level)
+            .checksum(ContentChecksum::ChecksumEnabled)
+            .build(w)?;
+
+        Ok(Lz4Compressor { encoder: encoder })
+    }
+}
+
+impl io::Write for Lz4Compressor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+impl super::Compressor for Lz4Compressor {
+    fn finalize(self: Box<Self>) -> Result<Box<Cryptor>, Error> {
+        let (inner, result) = self.encoder.finish();
+        result?;
+        Ok(inner)
+    }
+}
+
+pub struct Lz4Decompressor {
+    decoder: Decoder<Box<Decryptor>>,
+}
+
+impl Lz4Decompressor {
+    pub fn new(r: Box<Decryptor>) -> Result<Self, Error> {
+        Ok(Lz4Decompressor {
+            decoder: Decoder::new(r)?,
+        })
+    }
+}
+
+impl io::Read for Lz4Decompressor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buf)
+    }
+}
+
+impl super::Decompressor for Lz4Decompressor {
+    fn finalize(mut self: Box<Self>) -> Result<Box<Decryptor>, Error> {
+        // drain the rest of the frame so the content checksum gets verified
+        io::copy(&mut self.decoder, &mut io::sink())?;
+        let (inner, result) = self.decoder.finish();
+        result?;
+        Ok(inner)
+    }
+}
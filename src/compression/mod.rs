@@ -1,12 +1,59 @@
 pub(crate) mod identity;
 pub(crate) mod gzip;
+pub(crate) mod zstd;
+pub(crate) mod xz;
+pub(crate) mod lz4;
 
 use std::io;
 
 use anyhow::Error;
 
-use crate::encryption::Cryptor;
+use crate::config;
+use crate::encryption::{Cryptor, Decryptor};
+
+/// The compression a backup was written with, recorded in its object names.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum CompressionKind {
+    Gzip,
+    Zstd,
+    Xz,
+    Lz4,
+}
+
+impl CompressionKind {
+    pub fn of(typ: &config::CompressionType) -> CompressionKind {
+        match typ {
+            config::CompressionType::Gzip { .. } => CompressionKind::Gzip,
+            config::CompressionType::Zstd { .. } => CompressionKind::Zstd,
+            config::CompressionType::Xz { .. } => CompressionKind::Xz,
+            config::CompressionType::Lz4 { .. } => CompressionKind::Lz4,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CompressionKind::Gzip => "gz",
+            CompressionKind::Zstd => "zst",
+            CompressionKind::Xz => "xz",
+            CompressionKind::Lz4 => "lz4",
+        }
+    }
+
+    pub fn from_extension(ext: &str) -> Option<CompressionKind> {
+        match ext {
+            "gz" => Some(CompressionKind::Gzip),
+            "zst" => Some(CompressionKind::Zstd),
+            "xz" => Some(CompressionKind::Xz),
+            "lz4" => Some(CompressionKind::Lz4),
+            _ => None,
+        }
+    }
+}
 
 pub trait Compressor: io::Write {
     fn finalize(self: Box<Self>) -> Result<Box<dyn Cryptor>, Error>;
 }
+
+pub trait Decompressor: io::Read {
+    fn finalize(self: Box<Self>) -> Result<Box<dyn Decryptor>, Error>;
+}
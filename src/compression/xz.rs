@@ -0,0 +1,83 @@
+
+use std::io;
+
+use crate::encryption::{Cryptor, Decryptor};
+
+use anyhow::Error;
+
+use xz2::stream::{Check, MtStreamBuilder, Stream};
+use xz2::write::XzEncoder;
+use xz2::read::XzDecoder;
+
+/// Highest xz preset, the same as `xz -9`.
+const MAX_LEVEL: u32 = 9;
+
+pub struct XzCompressor {
+    encoder: XzEncoder<Box<Cryptor>>,
+}
+
+impl XzCompressor {
+    pub fn new(w: Box<Cryptor>, level: u32, threads: u32) -> Result<Self, Error> {
+        if level > MAX_LEVEL {
+            bail!("xz compression level must be between 0 and {}, got {}", MAX_LEVEL, level);
+        }
+
+        let stream = if threads > 1 {
+            debug!("compressing with {} xz worker threads", threads);
+            MtStreamBuilder::new()
+                .preset(level)
+                .threads(threads)
+                .check(Check::Crc64)
+                .encoder()?
+        } else {
+            Stream::new_easy_encoder(level, Check::Crc64)?
+        };
+
+        Ok(XzCompressor {
+            encoder: XzEncoder::new_stream(w, stream),
+        })
+    }
+}
+
+impl io::Write for XzCompressor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+impl super::Compressor for XzCompressor {
+    fn finalize(self: Box<Self>) -> Result<Box<Cryptor>, Error> {
+        let inner = self.encoder.finish()?;
+        Ok(inner)
+    }
+}
+
+pub struct XzDecompressor {
+    decoder: XzDecoder<Box<Decryptor>>,
+}
+
+impl XzDecompressor {
+    pub fn new(r: Box<Decryptor>) -> Self {
+        XzDecompressor {
+            decoder: XzDecoder::new_multi_decoder(r),
+        }
+    }
+}
+
+impl io::Read for XzDecompressor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buf)
+    }
+}
+
+impl super::Decompressor for XzDecompressor {
+    fn finalize(mut self: Box<Self>) -> Result<Box<Decryptor>, Error> {
+        // drain the rest of the stream so the xz index and check get verified
+        io::copy(&mut self.decoder, &mut io::sink())?;
+        Ok(self.decoder.into_inner())
+    }
+}
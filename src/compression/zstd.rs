@@ -0,0 +1,84 @@
+
+use std::io;
+
+use crate::encryption::{Cryptor, Decryptor};
+
+use anyhow::Error;
+
+use zstd::stream::raw::{self, CParameter};
+use zstd::stream::read::Decoder;
+use zstd::stream::zio::Writer;
+
+/// Highest zstd compression level. Levels above 19 use a lot of memory for
+/// both compression and decompression.
+const MAX_LEVEL: i32 = 22;
+
+pub struct ZstdCompressor {
+    encoder: Writer<Box<Cryptor>, raw::Encoder>,
+}
+
+impl ZstdCompressor {
+    pub fn new(w: Box<Cryptor>, level: i32, threads: u32, long_distance_matching: bool) -> Result<Self, Error> {
+        if level < 1 || level > MAX_LEVEL {
+            bail!("zstd compression level must be between 1 and {}, got {}", MAX_LEVEL, level);
+        }
+
+        let mut encoder = raw::Encoder::new(level)?;
+        if threads > 0 {
+            debug!("compressing with {} zstd worker threads", threads);
+            encoder.set_parameter(CParameter::NbWorkers(threads))?;
+        }
+        if long_distance_matching {
+            encoder.set_parameter(CParameter::EnableLongDistanceMatching(true))?;
+        }
+        encoder.set_parameter(CParameter::ChecksumFlag(true))?;
+
+        Ok(ZstdCompressor {
+            encoder: Writer::new(w, encoder),
+        })
+    }
+}
+
+impl io::Write for ZstdCompressor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+impl super::Compressor for ZstdCompressor {
+    fn finalize(mut self: Box<Self>) -> Result<Box<Cryptor>, Error> {
+        self.encoder.finish()?;
+        let (inner, _) = self.encoder.into_inner();
+        Ok(inner)
+    }
+}
+
+pub struct ZstdDecompressor {
+    decoder: Decoder<io::BufReader<Box<Decryptor>>>,
+}
+
+impl ZstdDecompressor {
+    pub fn new(r: Box<Decryptor>) -> Result<Self, Error> {
+        Ok(ZstdDecompressor {
+            decoder: Decoder::new(r)?,
+        })
+    }
+}
+
+impl io::Read for ZstdDecompressor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buf)
+    }
+}
+
+impl super::Decompressor for ZstdDecompressor {
+    fn finalize(mut self: Box<Self>) -> Result<Box<Decryptor>, Error> {
+        // drain the rest of the stream so the frame checksum gets verified
+        io::copy(&mut self.decoder, &mut io::sink())?;
+        Ok(self.decoder.finish().into_inner())
+    }
+}
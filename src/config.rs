@@ -29,16 +29,21 @@ pub struct Config {
     pub encryption: Option<Vec<Encryption>>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum JobType {
     #[serde(rename = "full")]
     Full,
     #[serde(rename = "differential")]
-    Differential { full_backup_schedule: String }
+    Differential { full_backup_schedule: String },
+    /// Compares against the most recent backup of any kind. A new full
+    /// backup is taken when the schedule says so or when the chain since the
+    /// last full backup reaches `max_chain_length` increments.
+    #[serde(rename = "incremental")]
+    Incremental { full_backup_schedule: String, max_chain_length: Option<usize> },
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct Job {
     pub name: String,
     #[serde(flatten)]
@@ -47,34 +52,90 @@ pub struct Job {
     pub destination: String,
     pub compression: Option<String>,
     pub encryption: Option<String>,
+    pub retention: Option<Retention>,
+    pub tags: Option<Vec<String>>,
+    pub schedule: Option<String>,
+    /// Leave plain text paths out of the manifest, defaults to true.
+    pub obfuscate_manifest: Option<bool>,
+    pub change_detection: Option<ChangeDetection>,
+    /// Gitignore style patterns, relative to the root of the source.
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    /// Skip directories tagged with a valid `CACHEDIR.TAG`.
+    pub exclude_caches: Option<bool>,
+    /// Skip directories containing a file with one of these names, for
+    /// example `.nobackup`.
+    pub exclude_markers: Option<Vec<String>>,
+    /// Don't descend into other file systems mounted below the source,
+    /// except for the mounts listed in `include_mounts`.
+    pub one_file_system: Option<bool>,
+    pub include_mounts: Option<Vec<String>>,
+    /// Archive extended attributes, which includes SELinux labels, file
+    /// capabilities and POSIX ACLs.
+    pub xattrs: Option<bool>,
 }
 
-#[derive(Deserialize)]
+impl Job {
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.as_ref().map_or(false, |tags| tags.iter().any(|t| t == tag))
+    }
+}
+
+/// How a differential backup decides that a file is unchanged since the
+/// base backup. `metadata` compares mtime seconds, owner and mode, `strict`
+/// also compares size, inode, ctime and mtime nanoseconds, and `content`
+/// additionally hashes files that look unchanged.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ChangeDetection {
+    #[serde(rename = "metadata")]
+    Metadata,
+    #[serde(rename = "strict")]
+    Strict,
+    #[serde(rename = "content")]
+    Content,
+}
+
+/// Rules deciding which backups `prune` keeps. A backup is kept when any of
+/// the `keep_*` rules selects it, and removed once it is older than
+/// `max_age_days`. Backups that a kept backup is based on are always kept.
+#[derive(Deserialize, Clone, Default)]
+pub struct Retention {
+    pub keep_last_full: Option<usize>,
+    pub keep_daily: Option<usize>,
+    pub keep_weekly: Option<usize>,
+    pub keep_monthly: Option<usize>,
+    pub keep_yearly: Option<usize>,
+    pub max_age_days: Option<i64>,
+}
+
+#[derive(Deserialize, Clone)]
 pub struct Destination {
     pub name: String,
     #[serde(flatten)]
     pub typ: DestinationType,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum DestinationType {
     #[serde(rename = "s3")]
     S3 { region: String, bucket: String, prefix: String, access_key_id: String, secret_access_key: String },
     #[serde(rename = "file")]
     File { path: String },
+    #[serde(rename = "directory")]
+    Directory { path: String },
     #[serde(rename = "null")]
     Null,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct Source {
     pub name: String,
     #[serde(flatten)]
     pub typ: SourceType,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum SourceType {
     #[serde(rename = "lvm")]
@@ -83,32 +144,48 @@ pub enum SourceType {
     CephFS { mon: Option<String>, path: String, user: Option<String>, secret: Option<String> },
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct Compression {
     pub name: String,
     #[serde(flatten)]
     pub typ: CompressionType
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum CompressionType {
     #[serde(rename = "gzip")]
-    Gzip,
+    Gzip { level: Option<u32>, threads: Option<usize> },
+    #[serde(rename = "zstd")]
+    Zstd { level: Option<i32>, threads: Option<u32>, long_distance_matching: Option<bool> },
+    #[serde(rename = "xz")]
+    Xz { level: Option<u32>, threads: Option<u32> },
+    #[serde(rename = "lz4")]
+    Lz4 { level: Option<u32> },
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct Encryption {
     pub name: String,
     #[serde(flatten)]
     pub typ: EncryptionType,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum EncryptionType {
     #[serde(rename = "pgp")]
-    Pgp { pubkey_file: String }
+    Pgp {
+        pubkey_file: Option<String>,
+        pubkey_files: Option<Vec<String>>,
+        privkey_file: Option<String>,
+        signing_key_file: Option<String>,
+        verification_key_file: Option<String>,
+    },
+    #[serde(rename = "age")]
+    Age { recipients: Vec<String>, identity_file: Option<String> },
+    #[serde(rename = "symmetric")]
+    Symmetric { passphrase_file: Option<String>, key_file: Option<String>, max_work_factor: Option<u8> },
 }
 
 #[cfg(test)]
@@ -132,10 +209,61 @@ mod test {
         assert_eq!(source.name, "foo");
     }
 
+    #[test]
+    fn read_job_tags() {
+        let config = load_config(&config_path("job.toml")).unwrap();
+        let jobs = config.jobs.unwrap();
+        assert!(jobs[0].has_tag("nightly"));
+        assert!(!jobs[0].has_tag("weekly"));
+        assert!(!jobs[1].has_tag("nightly"));
+    }
+
+    #[test]
+    fn read_incremental_job() {
+        let config = load_config(&config_path("job.toml")).unwrap();
+        let jobs = config.jobs.unwrap();
+        match jobs[2].typ {
+            JobType::Incremental { max_chain_length: Some(14), .. } => (),
+            _ => panic!("expected incremental job"),
+        }
+    }
+
+    #[test]
+    fn read_job_patterns() {
+        let config = load_config(&config_path("job.toml")).unwrap();
+        let jobs = config.jobs.unwrap();
+        assert!(jobs[0].exclude.is_none());
+        assert_eq!(jobs[2].exclude.as_ref().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn read_job_change_detection() {
+        let config = load_config(&config_path("job.toml")).unwrap();
+        let jobs = config.jobs.unwrap();
+        assert_eq!(jobs[0].change_detection, None);
+        assert_eq!(jobs[1].change_detection, Some(ChangeDetection::Strict));
+    }
+
     #[test]
     fn read_simple_destination_config() {
         let config = load_config(&config_path("destination.toml")).unwrap();
         let destinations = &config.destinations.unwrap()[0];
         assert_eq!(destinations.name, "foo");
     }
+
+    #[test]
+    fn read_compression_config() {
+        let config = load_config(&config_path("compression.toml")).unwrap();
+        let compression = config.compression.unwrap();
+
+        match compression[0].typ {
+            CompressionType::Gzip { level: None, threads: None } => (),
+            _ => panic!("expected default gzip compression"),
+        }
+
+        match compression[1].typ {
+            CompressionType::Gzip { level: Some(6), threads: Some(4) } => (),
+            _ => panic!("expected parallel gzip compression"),
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,141 @@
+use super::config;
+use super::backup;
+
+use std::cmp;
+use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{self, atomic};
+use std::thread;
+use std::time;
+
+use anyhow::Error;
+
+use chrono::prelude::*;
+
+use cron::Schedule;
+
+/// How often the daemon wakes up to check for a config reload.
+const POLL_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+struct ScheduledJob {
+    name: String,
+    schedule: Schedule,
+    next: Option<DateTime<Utc>>,
+}
+
+fn load_schedules(config: &config::Config) -> Result<Vec<ScheduledJob>, Error> {
+    let jobs = match config.jobs {
+        Some(ref j) => j,
+        None => return Ok(Vec::new()),
+    };
+
+    let now = Utc::now();
+
+    jobs.iter()
+        .filter_map(|job| job.schedule.as_ref().map(|s| (job, s)))
+        .map(|(job, expr)| {
+            let schedule = Schedule::from_str(expr)
+                .map_err(|e| format_err!("failed to parse schedule of job '{}': {}", job.name, e))?;
+            let next = schedule.after(&now).nth(0);
+            match next {
+                Some(t) => info!("job '{}' is next scheduled for {}", job.name, t),
+                None => warn!("schedule of job '{}' has no upcoming runs", job.name),
+            }
+            Ok(ScheduledJob { name: job.name.clone(), schedule: schedule, next: next })
+        })
+        .collect()
+}
+
+/// Removes a job from the set of running jobs once its thread finishes,
+/// even if the backup panicked.
+struct RunningGuard {
+    name: String,
+    running: sync::Arc<sync::Mutex<HashSet<String>>>,
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        let mut running = self.running.lock().expect("mutex lock poisoned");
+        running.remove(&self.name);
+    }
+}
+
+pub fn run(config_path: &Path, config: config::Config) -> Result<(), Error> {
+    let reload = sync::Arc::new(atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGHUP, reload.clone())?;
+
+    let mut config = config;
+    let mut schedules = load_schedules(&config)?;
+    let running = sync::Arc::new(sync::Mutex::new(HashSet::new()));
+
+    if schedules.is_empty() {
+        warn!("no jobs with a schedule configured");
+    }
+
+    info!("scheduler started");
+    loop {
+        if reload.swap(false, atomic::Ordering::SeqCst) {
+            info!("reloading config from '{}'", config_path.display());
+            let reloaded = config::load_config(config_path)
+                .and_then(|c| load_schedules(&c).map(|s| (c, s)));
+
+            match reloaded {
+                Ok((c, s)) => {
+                    config = c;
+                    schedules = s;
+                    info!("config reloaded, {} scheduled jobs", schedules.len());
+                },
+                Err(e) => error!("failed to reload config, keeping the current one: {}", e),
+            }
+        }
+
+        let now = Utc::now();
+
+        for scheduled in schedules.iter_mut() {
+            match scheduled.next {
+                Some(next) if next <= now => (),
+                _ => continue,
+            }
+
+            scheduled.next = scheduled.schedule.after(&now).nth(0);
+
+            {
+                let mut running = running.lock().expect("mutex lock poisoned");
+                if running.contains(&scheduled.name) {
+                    warn!("job '{}' is still running, skipping this run", scheduled.name);
+                    continue;
+                }
+                running.insert(scheduled.name.clone());
+            }
+
+            let guard = RunningGuard { name: scheduled.name.clone(), running: running.clone() };
+
+            let job = match backup::load_job(&config, &scheduled.name) {
+                Ok(j) => j,
+                Err(e) => {
+                    error!("failed to load job '{}': {}", scheduled.name, e);
+                    continue;
+                },
+            };
+
+            info!("starting scheduled job '{}'", job.name);
+            thread::spawn(move || {
+                let _guard = guard;
+                let start = time::Instant::now();
+                match backup::backup(&job) {
+                    Ok(()) => info!("job '{}' succeeded in {}s", job.name, start.elapsed().as_secs()),
+                    Err(e) => error!("job '{}' failed after {}s: {:?}", job.name, start.elapsed().as_secs(), e),
+                }
+            });
+        }
+
+        let wait = schedules.iter()
+            .filter_map(|s| s.next)
+            .min()
+            .and_then(|next| (next - Utc::now()).to_std().ok())
+            .map_or(POLL_INTERVAL, |d| cmp::min(d, POLL_INTERVAL));
+
+        thread::sleep(wait);
+    }
+}
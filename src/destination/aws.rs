@@ -102,15 +102,18 @@ impl AwsBucket {
 
     fn get_object_name(&self, desc: &TargetDescriptor) -> String {
         let prefix = self.get_object_dir(&desc.host, &desc.job);
-        let ext = match desc.typ {
-            TargetType::Full => "full",
-            TargetType::Differential => "diff",
-        };
-        let time = desc.timestamp.to_rfc3339_opts( SecondsFormat::Secs, true);
-        format!("{}{}.{}", prefix, time, ext)
+        format!("{}{}", prefix, object_name(desc))
+    }
+
+    fn get_search_prefix(&self, search: &BackupSearchRequest) -> String {
+        match (&search.host, &search.job) {
+            (Some(host), Some(job)) => self.get_object_dir(host, job),
+            (Some(host), None) => format!("{}{}/", self.prefix, host),
+            (None, _) => self.prefix.clone(),
+        }
     }
 
-    fn parse_object(&self, host: &str, job: &str, obj: &s3::Object) -> Option<TargetDescriptor> {
+    fn parse_object(&self, obj: &s3::Object) -> Option<TargetDescriptor> {
         let key = match obj.key {
             Some(ref x) => x.to_string(),
             _ => return None,
@@ -118,38 +121,22 @@ impl AwsBucket {
 
         trace!("evaluating object '{}'", key);
 
-        let prefix = self.get_object_dir(host, job);
-        let name = key.trim_start_matches(&prefix);
-        let parts: Vec<&str> = name.splitn(2, ".").collect();
-
-        if parts.len() < 2 {
-            trace!("expected 2 parts, found {}", parts.len());
-            return None;
-        }
-
-        let timestamp = match DateTime::parse_from_rfc3339(parts[0]) {
-            Ok(t) => DateTime::<Utc>::from_utc(t.naive_utc(), Utc),
-            Err(_) => {
-                trace!("datetime '{}' could not be parsed", parts[0]);
+        let rest = match key.strip_prefix(self.prefix.as_str()) {
+            Some(rest) => rest,
+            None => {
+                trace!("object is outside of prefix '{}'", self.prefix);
                 return None;
             },
         };
 
-        let typ = match parts[1] {
-            "full.manifest" => TargetType::Full,
-            "diff.manifest" => TargetType::Differential,
-            part => {
-                trace!("backup type '{}' could not be parsed", part);
-                return None;
-            },
-        };
+        let path: Vec<&str> = rest.split('/').collect();
+
+        if path.len() != 3 {
+            trace!("expected host, job and name, found {} path segments", path.len());
+            return None;
+        }
 
-        Some(TargetDescriptor {
-            host: host.into(),
-            job: job.into(),
-            timestamp: timestamp.into(),
-            typ: typ,
-        })
+        parse_manifest_name(path[0], path[1], path[2])
     }
 }
 
@@ -164,10 +151,7 @@ enum ObjectType {
 }
 
 fn get_object_tags(desc: &TargetDescriptor, kind: ObjectType) -> String {
-    let backup_type = match desc.kind() {
-        TargetType::Full => "full",
-        TargetType::Differential => "diff",
-    };
+    let backup_type = desc.kind().extension();
 
     let object_type = match kind {
         ObjectType::Manifest => "manifest",
@@ -184,7 +168,7 @@ impl Destination for AwsBucket {
 
         let mut backups = Vec::new();
         let mut token = None;
-        let dir = self.get_object_dir(&search.host, &search.job);
+        let dir = self.get_search_prefix(search);
 
         debug!("enumerating aws objects in '{}'", &dir);
         loop {
@@ -202,7 +186,9 @@ impl Destination for AwsBucket {
 
             if let Some(objs) = result.contents {
                 trace!("found {} objects", objs.len());
-                backups.extend(objs.iter().filter_map(|obj| self.parse_object(&search.host, &search.job, obj)));
+                backups.extend(objs.iter()
+                    .filter_map(|obj| self.parse_object(obj))
+                    .filter(|desc| search.matches(&desc.host, &desc.job)));
             } else {
                 trace!("response contained no objects");
             }
@@ -364,6 +350,82 @@ impl Destination for AwsBucket {
             state: state,
         }))
     }
+
+    fn stat(&self, desc: &TargetDescriptor) -> Result<TargetInfo, Error> {
+        let client = self.get_client()?;
+        let name = self.get_object_name(desc);
+
+        let mut head_req = s3::HeadObjectRequest::default();
+        head_req.bucket = self.bucket.clone();
+        head_req.key = name;
+
+        let resp = client.head_object(head_req).sync()?;
+        let size = resp.content_length.unwrap_or(0) as u64;
+        // s3 omits the storage class header for standard objects
+        let storage_class = resp.storage_class.or_else(|| Some("STANDARD".into()));
+
+        Ok(TargetInfo::new(size, storage_class))
+    }
+
+    fn delete(&self, desc: &TargetDescriptor) -> Result<(), Error> {
+        let client = self.get_client()?;
+        let name = self.get_object_name(desc);
+
+        // remove the manifest first so an interrupted delete never leaves a
+        // listed backup without its data
+        for key in vec![format!("{}.manifest", name), name] {
+            let mut delete_req = s3::DeleteObjectRequest::default();
+            delete_req.bucket = self.bucket.clone();
+            delete_req.key = key.clone();
+
+            debug!("deleting s3 object {}", key);
+            client.delete_object(delete_req).sync()
+                .context(format!("failed to delete '{}'", key))?;
+        }
+
+        Ok(())
+    }
+
+    fn open(&self, desc: &TargetDescriptor) -> Result<Box<dyn TargetReader>, Error> {
+        let client = self.get_client()?;
+        let name = self.get_object_name(desc);
+
+        let mut get_req = s3::GetObjectRequest::default();
+        get_req.bucket = self.bucket.clone();
+        get_req.key = name.clone();
+
+        info!("downloading s3 object {}", name);
+        let resp = client.get_object(get_req).sync()
+            .context(format!("failed to download '{}', archived objects must be restored first", name))?;
+        let body = resp.body.ok_or_else(|| format_err!("no body on response"))?;
+
+        Ok(Box::new(AwsDownload {
+            key: name,
+            body: Box::new(body.into_blocking_read()),
+            read: 0,
+        }))
+    }
+}
+
+pub struct AwsDownload {
+    key: String,
+    body: Box<dyn io::Read + Send>,
+    read: u64,
+}
+
+impl io::Read for AwsDownload {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.body.read(buf)?;
+        self.read += read as u64;
+        Ok(read)
+    }
+}
+
+impl TargetReader for AwsDownload {
+    fn finalize(self: Box<Self>) -> Result<(), Error> {
+        trace!("read {} bytes from s3 object {}", self.read, self.key);
+        Ok(())
+    }
 }
 
 impl io::Write for AwsUpload {
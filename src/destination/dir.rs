@@ -0,0 +1,343 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::*;
+
+use anyhow::{Error, Context};
+
+use uuid::Uuid;
+
+/// Stores backups in a local directory using the same
+/// `<host>/<job>/<timestamp>.<type>[.tar...][.manifest]` layout as the s3 bucket.
+pub struct DirectoryDestination {
+    path: PathBuf,
+}
+
+impl DirectoryDestination {
+    pub fn new<P: Into<PathBuf>>(path: P) -> DirectoryDestination {
+        DirectoryDestination { path: path.into() }
+    }
+
+    fn get_object_dir(&self, host: &str, job: &str) -> PathBuf {
+        self.path.join(host).join(job)
+    }
+
+    fn get_object_path(&self, desc: &TargetDescriptor) -> PathBuf {
+        self.get_object_dir(&desc.host, &desc.job).join(object_name(desc))
+    }
+
+    fn get_manifest_path(&self, desc: &TargetDescriptor) -> PathBuf {
+        self.get_object_dir(&desc.host, &desc.job).join(format!("{}.manifest", object_name(desc)))
+    }
+}
+
+fn list_dirs(path: &Path) -> Result<Vec<String>, Error> {
+    let mut names = Vec::new();
+
+    let entries = match fs::read_dir(path) {
+        Ok(e) => e,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(names),
+        Err(e) => return Err(e.into()),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        match entry.file_name().into_string() {
+            Ok(name) => names.push(name),
+            Err(name) => trace!("skipping non utf-8 directory '{}'", name.to_string_lossy()),
+        }
+    }
+
+    Ok(names)
+}
+
+/// Creates a temporary file next to `path` that is moved into place once the
+/// data has been written and synced.
+fn create_temp(path: &Path) -> Result<(fs::File, PathBuf), Error> {
+    let dir = path.parent()
+        .ok_or_else(|| format_err!("path '{}' has no parent", path.display()))?;
+    fs::create_dir_all(dir)
+        .context(format!("failed to create directory '{}'", dir.display()))?;
+
+    let name = path.file_name()
+        .ok_or_else(|| format_err!("path '{}' has no file name", path.display()))?;
+    let temp = dir.join(format!(".{}.{}.tmp", name.to_string_lossy(), Uuid::new_v4()));
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp)
+        .context(format!("failed to create temp file '{}'", temp.display()))?;
+
+    Ok((file, temp))
+}
+
+fn remove_temp(temp: &Path) {
+    debug!("removing unfinished file '{}'", temp.display());
+    match fs::remove_file(temp) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => warn!("failed to remove '{}': {}", temp.display(), e),
+    }
+}
+
+fn commit_temp(file: fs::File, temp: &Path, path: &Path) -> Result<(), Error> {
+    file.sync_all()?;
+    drop(file);
+
+    trace!("renaming '{}' to '{}'", temp.display(), path.display());
+    fs::rename(temp, path)
+        .context(format!("failed to move '{}' into place", path.display()))?;
+
+    if let Some(dir) = path.parent() {
+        fs::File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+impl Destination for DirectoryDestination {
+
+    fn list_backups(&self, search: &BackupSearchRequest) -> Result<Vec<TargetDescriptor>, Error> {
+        let mut backups = Vec::new();
+
+        debug!("enumerating backups in '{}'", self.path.display());
+        for host in list_dirs(&self.path)? {
+            for job in list_dirs(&self.path.join(&host))? {
+                if !search.matches(&host, &job) {
+                    continue;
+                }
+
+                for entry in fs::read_dir(self.get_object_dir(&host, &job))? {
+                    let entry = entry?;
+                    let name = entry.file_name();
+                    trace!("evaluating file '{}'", name.to_string_lossy());
+
+                    if let Some(desc) = name.to_str().and_then(|n| parse_manifest_name(&host, &job, n)) {
+                        backups.push(desc);
+                    }
+                }
+            }
+        }
+
+        debug!("found {} valid backup manifests", backups.len());
+
+        Ok(backups)
+    }
+
+    fn fetch_manifest(&self, desc: &TargetDescriptor) -> Result<Vec<u8>, Error> {
+        let path = self.get_manifest_path(desc);
+        let data = fs::read(&path)
+            .context(format!("failed to read manifest '{}'", path.display()))?;
+        Ok(data)
+    }
+
+    fn upload_manifest(&self, desc: &TargetDescriptor, data: &[u8]) -> Result<(), Error> {
+        let path = self.get_manifest_path(desc);
+        let (mut file, temp) = create_temp(&path)?;
+        let result = file.write_all(data)
+            .map_err(Error::from)
+            .and_then(|_| commit_temp(file, &temp, &path));
+        if result.is_err() {
+            remove_temp(&temp);
+        }
+        result
+    }
+
+    fn allocate(&self, desc: &TargetDescriptor, _: u64) -> Result<Box<Target>, Error> {
+        let path = self.get_object_path(desc);
+        let (file, temp) = create_temp(&path)?;
+        info!("writing backup data to '{}'", temp.display());
+
+        Ok(Box::new(DirectoryTarget {
+            writer: Some(io::BufWriter::new(file)),
+            temp: temp,
+            path: path,
+            committed: false,
+        }))
+    }
+
+    fn open(&self, desc: &TargetDescriptor) -> Result<Box<dyn TargetReader>, Error> {
+        let path = self.get_object_path(desc);
+        let file = fs::File::open(&path)
+            .context(format!("failed to open '{}'", path.display()))?;
+        Ok(Box::new(DirectoryReader { file: file }))
+    }
+
+    fn stat(&self, desc: &TargetDescriptor) -> Result<TargetInfo, Error> {
+        let metadata = fs::metadata(self.get_object_path(desc))?;
+        Ok(TargetInfo::new(metadata.len(), None))
+    }
+
+    fn delete(&self, desc: &TargetDescriptor) -> Result<(), Error> {
+        for path in vec![self.get_manifest_path(desc), self.get_object_path(desc)] {
+            debug!("deleting file '{}'", path.display());
+            match fs::remove_file(&path) {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => warn!("file '{}' already removed", path.display()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes a backup into a temp file, which is removed again if the target is
+/// dropped without being finalized, for example when the backup fails.
+pub struct DirectoryTarget {
+    writer: Option<io::BufWriter<fs::File>>,
+    temp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl DirectoryTarget {
+    fn writer(&mut self) -> io::Result<&mut io::BufWriter<fs::File>> {
+        self.writer.as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "backup file already finalized"))
+    }
+}
+
+impl io::Write for DirectoryTarget {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer()?.flush()
+    }
+}
+
+impl Target for DirectoryTarget {
+    fn finalize(mut self: Box<Self>) -> Result<(), Error> {
+        let writer = self.writer.take()
+            .ok_or_else(|| format_err!("backup file already finalized"))?;
+        let file = writer.into_inner()
+            .map_err(|e| format_err!("failed to flush backup file: {}", e.error()))?;
+        info!("finalizing backup file '{}'", self.path.display());
+        commit_temp(file, &self.temp, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for DirectoryTarget {
+    fn drop(&mut self) {
+        if !self.committed {
+            // close the file before removing it
+            drop(self.writer.take());
+            remove_temp(&self.temp);
+        }
+    }
+}
+
+pub struct DirectoryReader {
+    file: fs::File,
+}
+
+impl io::Read for DirectoryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl TargetReader for DirectoryReader {
+    fn finalize(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    use chrono::TimeZone;
+
+    use tempfile::TempDir;
+
+    fn descriptor(kind: TargetType) -> TargetDescriptor {
+        TargetDescriptor::new("host", "job", Utc.timestamp(1500000000, 0), kind)
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let dir = TempDir::new().unwrap();
+        let destination = DirectoryDestination::new(dir.path());
+        let desc = descriptor(TargetType::Full);
+
+        let mut target = destination.allocate(&desc, 0).unwrap();
+        target.write_all(b"data").unwrap();
+        target.finalize().unwrap();
+        destination.upload_manifest(&desc, b"manifest").unwrap();
+
+        let mut reader = destination.open(&desc).unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"data");
+
+        assert_eq!(destination.fetch_manifest(&desc).unwrap(), b"manifest");
+        assert_eq!(destination.stat(&desc).unwrap().size(), 4);
+    }
+
+    #[test]
+    fn list_written_backups() {
+        let dir = TempDir::new().unwrap();
+        let destination = DirectoryDestination::new(dir.path());
+        let full = descriptor(TargetType::Full);
+        let diff = descriptor(TargetType::Differential);
+        let incr = descriptor(TargetType::Incremental);
+
+        destination.upload_manifest(&full, b"").unwrap();
+        destination.upload_manifest(&diff, b"").unwrap();
+        destination.upload_manifest(&incr, b"").unwrap();
+
+        let backups = destination.list_backups(&BackupSearchRequest::new("host", "job")).unwrap();
+        assert_eq!(backups.len(), 3);
+        assert!(backups.iter().any(|b| b.kind() == TargetType::Incremental));
+
+        let backups = destination.list_backups(&BackupSearchRequest::new("other", "job")).unwrap();
+        assert!(backups.is_empty());
+
+        let backups = destination.list_backups(&BackupSearchRequest::filter(None, None)).unwrap();
+        assert_eq!(backups.len(), 3);
+    }
+
+    #[test]
+    fn unfinished_targets_are_not_listed() {
+        let dir = TempDir::new().unwrap();
+        let destination = DirectoryDestination::new(dir.path());
+        let desc = descriptor(TargetType::Full);
+
+        let mut target = destination.allocate(&desc, 0).unwrap();
+        target.write_all(b"data").unwrap();
+
+        assert!(destination.open(&desc).is_err());
+        assert!(destination.list_backups(&BackupSearchRequest::new("host", "job")).unwrap().is_empty());
+
+        drop(target);
+        let leftover = fs::read_dir(destination.get_object_dir("host", "job")).unwrap().count();
+        assert_eq!(leftover, 0);
+    }
+
+    #[test]
+    fn delete_backup() {
+        let dir = TempDir::new().unwrap();
+        let destination = DirectoryDestination::new(dir.path());
+        let desc = descriptor(TargetType::Full);
+
+        let target = destination.allocate(&desc, 0).unwrap();
+        target.finalize().unwrap();
+        destination.upload_manifest(&desc, b"").unwrap();
+        destination.delete(&desc).unwrap();
+
+        assert!(destination.list_backups(&BackupSearchRequest::new("host", "job")).unwrap().is_empty());
+        assert!(destination.open(&desc).is_err());
+    }
+}
@@ -19,21 +19,35 @@ impl FileDescriptorDestination {
 impl super::Destination for FileDescriptorDestination {
 
     fn list_backups(&self, _: &super::BackupSearchRequest) -> Result<Vec<super::TargetDescriptor>, Error> {
-        unimplemented!();
+        // nothing written here can be found again, so jobs always run full backups
+        Ok(Vec::new())
     }
 
     fn fetch_manifest(&self, _: &super::TargetDescriptor) -> Result<Vec<u8>, Error> {
-        unimplemented!();
+        bail!("file descriptor destination does not support reading manifests");
     }
 
     fn upload_manifest(&self, _: &super::TargetDescriptor, _: &[u8]) -> Result<(), Error> {
-        unimplemented!();
+        warn!("file descriptor destination does not store manifests, discarding it");
+        Ok(())
     }
 
     fn allocate(&self, _: &super::TargetDescriptor, _: u64) -> Result<Box<super::Target>, Error> {
         let fd = self.file.try_clone()?;
         Ok(Box::new(FileDescriptorTarget { file: fd }))
     }
+
+    fn open(&self, _: &super::TargetDescriptor) -> Result<Box<super::TargetReader>, Error> {
+        bail!("file descriptor destination does not support reading backups");
+    }
+
+    fn stat(&self, _: &super::TargetDescriptor) -> Result<super::TargetInfo, Error> {
+        bail!("file descriptor destination does not support inspecting backups");
+    }
+
+    fn delete(&self, _: &super::TargetDescriptor) -> Result<(), Error> {
+        bail!("file descriptor destination does not support deleting backups");
+    }
 }
 
 pub struct FileDescriptorTarget {
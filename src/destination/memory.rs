@@ -0,0 +1,56 @@
+use std::io;
+use std::sync;
+
+use anyhow::Error;
+
+/// Collects written data in a shared buffer, used to run small objects like
+/// manifests through the compression and encryption pipeline.
+pub struct MemoryTarget {
+    buffer: sync::Arc<sync::Mutex<Vec<u8>>>,
+}
+
+impl MemoryTarget {
+    pub fn new(buffer: sync::Arc<sync::Mutex<Vec<u8>>>) -> MemoryTarget {
+        MemoryTarget { buffer: buffer }
+    }
+}
+
+impl io::Write for MemoryTarget {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut locked = self.buffer.lock().expect("mutex lock poisoned");
+        locked.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl super::Target for MemoryTarget {
+    fn finalize(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+pub struct MemoryReader {
+    cursor: io::Cursor<Vec<u8>>,
+}
+
+impl MemoryReader {
+    pub fn new(data: Vec<u8>) -> MemoryReader {
+        MemoryReader { cursor: io::Cursor::new(data) }
+    }
+}
+
+impl io::Read for MemoryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.cursor.read(buf)
+    }
+}
+
+impl super::TargetReader for MemoryReader {
+    fn finalize(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
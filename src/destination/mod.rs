@@ -1,24 +1,58 @@
 pub(crate) mod aws;
 pub(crate) mod fd;
 pub(crate) mod null;
+pub(crate) mod dir;
+pub(crate) mod memory;
 
 use std::io;
 
+use crate::compression::CompressionKind;
+use crate::encryption::EncryptionKind;
+
 use anyhow::Error;
 
 use chrono::prelude::*;
 
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub enum TargetType {
     Full,
     Differential,
+    Incremental,
+}
+
+impl TargetType {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TargetType::Full => "full",
+            TargetType::Differential => "diff",
+            TargetType::Incremental => "incr",
+        }
+    }
+
+    pub fn from_extension(ext: &str) -> Option<TargetType> {
+        match ext {
+            "full" => Some(TargetType::Full),
+            "diff" => Some(TargetType::Differential),
+            "incr" => Some(TargetType::Incremental),
+            _ => None,
+        }
+    }
 }
 
+/// How the objects of a backup were compressed and encrypted.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Encoding {
+    pub compression: Option<CompressionKind>,
+    pub encryption: Option<EncryptionKind>,
+}
+
+#[derive(Clone)]
 pub struct TargetDescriptor {
     host: String,
     job: String,
     timestamp: DateTime<Utc>,
     typ: TargetType,
+    encoding: Option<Encoding>,
 }
 
 impl TargetDescriptor {
@@ -30,9 +64,17 @@ impl TargetDescriptor {
             job: job.into(),
             timestamp: timestamp.into(),
             typ: typ,
+            encoding: None,
         }
     }
 
+    /// Records the encoding in the object names, so the backup can still be
+    /// read after the job's compression or encryption changed.
+    pub fn with_encoding(mut self, encoding: Encoding) -> TargetDescriptor {
+        self.encoding = Some(encoding);
+        self
+    }
+
     pub fn host(&self) -> &str {
         &self.host
     }
@@ -48,11 +90,96 @@ impl TargetDescriptor {
     pub fn timestamp(&self) -> &DateTime<Utc> {
         &self.timestamp
     }
+
+    /// Returns the recorded encoding. Backups written before it was recorded
+    /// have none.
+    pub fn encoding(&self) -> Option<&Encoding> {
+        self.encoding.as_ref()
+    }
+}
+
+/// Returns the file name of a backup's data object, the manifest is stored
+/// next to it with an additional `.manifest` extension. A recorded encoding
+/// is appended as `.tar[.<compression>][.<encryption>]`.
+pub(crate) fn object_name(desc: &TargetDescriptor) -> String {
+    let time = desc.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let mut name = format!("{}.{}", time, desc.typ.extension());
+
+    if let Some(ref encoding) = desc.encoding {
+        name.push_str(".tar");
+        if let Some(compression) = encoding.compression {
+            name.push('.');
+            name.push_str(compression.extension());
+        }
+        if let Some(encryption) = encoding.encryption {
+            name.push('.');
+            name.push_str(encryption.extension());
+        }
+    }
+
+    name
+}
+
+/// Parses the file name of a manifest object back into a descriptor.
+pub(crate) fn parse_manifest_name(host: &str, job: &str, name: &str) -> Option<TargetDescriptor> {
+    let parts: Vec<&str> = name.splitn(2, ".").collect();
+
+    if parts.len() < 2 {
+        trace!("expected 2 parts, found {}", parts.len());
+        return None;
+    }
+
+    let timestamp = match DateTime::parse_from_rfc3339(parts[0]) {
+        Ok(t) => DateTime::<Utc>::from_utc(t.naive_utc(), Utc),
+        Err(_) => {
+            trace!("datetime '{}' could not be parsed", parts[0]);
+            return None;
+        },
+    };
+
+    if !parts[1].ends_with(".manifest") {
+        trace!("object '{}' is not a manifest", name);
+        return None;
+    }
+
+    let mut exts = parts[1][..parts[1].len() - ".manifest".len()].split('.');
+    let ext = exts.next().unwrap_or("");
+    let typ = match TargetType::from_extension(ext) {
+        Some(t) => t,
+        None => {
+            trace!("backup type '{}' could not be parsed", ext);
+            return None;
+        },
+    };
+
+    let desc = TargetDescriptor::new(host, job, timestamp, typ);
+
+    match exts.next() {
+        None => Some(desc),
+        Some("tar") => {
+            let mut encoding = Encoding { compression: None, encryption: None };
+            for ext in exts {
+                match (CompressionKind::from_extension(ext), EncryptionKind::from_extension(ext)) {
+                    (Some(c), _) if encoding.compression.is_none() && encoding.encryption.is_none() => encoding.compression = Some(c),
+                    (_, Some(e)) if encoding.encryption.is_none() => encoding.encryption = Some(e),
+                    _ => {
+                        trace!("encoding '{}' of object '{}' could not be parsed", ext, name);
+                        return None;
+                    },
+                }
+            }
+            Some(desc.with_encoding(encoding))
+        },
+        Some(ext) => {
+            trace!("unexpected extension '{}' in object '{}'", ext, name);
+            None
+        },
+    }
 }
 
 pub struct BackupSearchRequest {
-    host: String,
-    job: String,
+    host: Option<String>,
+    job: Option<String>,
 }
 
 impl BackupSearchRequest {
@@ -60,10 +187,43 @@ impl BackupSearchRequest {
         where S: Into<String>, T: Into<String>
     {
         BackupSearchRequest {
-            host: host.into(),
-            job: job.into(),
+            host: Some(host.into()),
+            job: Some(job.into()),
+        }
+    }
+
+    pub fn filter(host: Option<String>, job: Option<String>) -> BackupSearchRequest {
+        BackupSearchRequest {
+            host: host,
+            job: job,
+        }
+    }
+
+    pub fn matches(&self, host: &str, job: &str) -> bool {
+        self.host.as_ref().map_or(true, |h| h == host) && self.job.as_ref().map_or(true, |j| j == job)
+    }
+}
+
+pub struct TargetInfo {
+    size: u64,
+    storage_class: Option<String>,
+}
+
+impl TargetInfo {
+    pub fn new(size: u64, storage_class: Option<String>) -> TargetInfo {
+        TargetInfo {
+            size: size,
+            storage_class: storage_class,
         }
     }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn storage_class(&self) -> Option<&str> {
+        self.storage_class.as_ref().map(|s| s.as_str())
+    }
 }
 
 pub trait Destination {
@@ -71,12 +231,54 @@ pub trait Destination {
     fn fetch_manifest(&self, desc: &TargetDescriptor) -> Result<Vec<u8>, Error>;
     fn upload_manifest(&self, desc: &TargetDescriptor, data: &[u8]) -> Result<(), Error>;
     fn allocate(&self, desc: &TargetDescriptor, size_hint: u64) -> Result<Box<Target>, Error>;
+    fn open(&self, desc: &TargetDescriptor) -> Result<Box<dyn TargetReader>, Error>;
+    fn stat(&self, desc: &TargetDescriptor) -> Result<TargetInfo, Error>;
+    fn delete(&self, desc: &TargetDescriptor) -> Result<(), Error>;
 }
 
 pub trait Target: io::Write + Sync {
     fn finalize(self: Box<Self>) -> Result<(), Error>;
 }
 
+pub trait TargetReader: io::Read + Send {
+    fn finalize(self: Box<Self>) -> Result<(), Error>;
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
 
+    fn round_trip(desc: &TargetDescriptor) -> TargetDescriptor {
+        let name = format!("{}.manifest", object_name(desc));
+        parse_manifest_name("host", "job", &name).unwrap()
+    }
+
+    #[test]
+    fn object_names_record_encoding() {
+        let time = Utc.timestamp(1500000000, 0);
+
+        let legacy = TargetDescriptor::new("host", "job", time, TargetType::Full);
+        assert_eq!(object_name(&legacy), "2017-07-14T02:40:00Z.full");
+        assert_eq!(round_trip(&legacy).encoding(), None);
+
+        let plain = Encoding { compression: None, encryption: None };
+        let desc = TargetDescriptor::new("host", "job", time, TargetType::Incremental).with_encoding(plain);
+        assert_eq!(object_name(&desc), "2017-07-14T02:40:00Z.incr.tar");
+        assert_eq!(round_trip(&desc).encoding(), Some(&plain));
 
+        let encoded = Encoding { compression: Some(CompressionKind::Gzip), encryption: Some(EncryptionKind::Pgp) };
+        let desc = TargetDescriptor::new("host", "job", time, TargetType::Differential).with_encoding(encoded);
+        assert_eq!(object_name(&desc), "2017-07-14T02:40:00Z.diff.tar.gz.pgp");
+        let parsed = round_trip(&desc);
+        assert_eq!(parsed.kind(), TargetType::Differential);
+        assert_eq!(parsed.encoding(), Some(&encoded));
+    }
 
+    #[test]
+    fn invalid_encodings_are_ignored() {
+        for name in &["2017-07-14T02:40:00Z.full.gz.manifest", "2017-07-14T02:40:00Z.full.tar.pgp.gz.manifest", "2017-07-14T02:40:00Z.full.tar.bz2.manifest"] {
+            assert!(parse_manifest_name("host", "job", name).is_none());
+        }
+    }
+}
@@ -9,15 +9,16 @@ pub struct NullDestination;
 
 impl super::Destination for NullDestination {
     fn list_backups(&self, _: &super::BackupSearchRequest) -> Result<Vec<super::TargetDescriptor>, Error> {
-        unimplemented!();
+        // everything is discarded, so there is never a base backup
+        Ok(Vec::new())
     }
 
     fn fetch_manifest(&self, _: &super::TargetDescriptor) -> Result<Vec<u8>, Error> {
-        unimplemented!();
+        bail!("null destination does not support reading manifests");
     }
 
     fn upload_manifest(&self, _: &super::TargetDescriptor, _: &[u8]) -> Result<(), Error> {
-        unimplemented!();
+        Ok(())
     }
 
     fn allocate(&self, _: &super::TargetDescriptor, _: u64) -> Result<Box<super::Target>, Error> {
@@ -31,6 +32,17 @@ impl super::Destination for NullDestination {
         }))
     }
 
+    fn open(&self, _: &super::TargetDescriptor) -> Result<Box<super::TargetReader>, Error> {
+        bail!("null destination does not support reading backups");
+    }
+
+    fn stat(&self, _: &super::TargetDescriptor) -> Result<super::TargetInfo, Error> {
+        bail!("null destination does not support inspecting backups");
+    }
+
+    fn delete(&self, _: &super::TargetDescriptor) -> Result<(), Error> {
+        bail!("null destination does not support deleting backups");
+    }
 }
 
 pub struct NullTarget {
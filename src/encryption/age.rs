@@ -0,0 +1,112 @@
+use std::io;
+use std::sync;
+
+use crate::destination::{Target, TargetReader};
+
+use super::ReadWrapper;
+
+use anyhow::{Error, Context};
+
+use age::{Encryptor, Decryptor, IdentityFile};
+use age::stream::{StreamWriter, StreamReader};
+use age::x25519;
+
+pub struct AgeCryptor {
+    writer: StreamWriter<Box<dyn Target>>,
+}
+
+impl AgeCryptor {
+    pub fn new(w: Box<dyn Target>, recipients: &[String]) -> Result<AgeCryptor, Error> {
+        if recipients.is_empty() {
+            bail!("no age recipients configured");
+        }
+
+        let recipients = recipients.iter()
+            .map(|r| {
+                let recipient = r.parse::<x25519::Recipient>()
+                    .map_err(|e| format_err!("invalid age recipient '{}': {}", r, e))?;
+                Ok(Box::new(recipient) as Box<dyn age::Recipient>)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        debug!("encrypting archive to {} age recipients", recipients.len());
+        let writer = Encryptor::with_recipients(recipients)
+            .wrap_output(w)
+            .context("failed to initialize encryptor")?;
+
+        Ok(AgeCryptor { writer: writer })
+    }
+}
+
+impl io::Write for AgeCryptor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl super::Cryptor for AgeCryptor {
+    fn finalize(self: Box<Self>) -> Result<Box<dyn Target>, Error> {
+        let target = self.writer.finish()?;
+        Ok(target)
+    }
+}
+
+pub struct AgeDecryptor {
+    source: sync::Arc<sync::Mutex<Box<dyn TargetReader>>>,
+    reader: StreamReader<ReadWrapper>,
+}
+
+impl AgeDecryptor {
+    pub fn new(r: Box<dyn TargetReader>, identity_file: &str) -> Result<AgeDecryptor, Error> {
+        let identities = IdentityFile::from_file(identity_file.into())
+            .context(format!("failed to read age identity file '{}'", identity_file))?
+            .into_identities();
+
+        if identities.is_empty() {
+            bail!("no age identities found in file");
+        }
+
+        let source = sync::Arc::new(sync::Mutex::new(r));
+
+        let decryptor = match Decryptor::new(ReadWrapper(source.clone()))
+            .context("failed to parse age header")?
+        {
+            Decryptor::Recipients(d) => d,
+            Decryptor::Passphrase(_) => bail!("archive is encrypted with a passphrase, not to recipients"),
+        };
+
+        let reader = decryptor.decrypt(identities.iter().map(|i| i as &dyn age::Identity))
+            .context("no usable age identity found to decrypt archive")?;
+
+        Ok(AgeDecryptor {
+            source: source,
+            reader: reader,
+        })
+    }
+}
+
+impl io::Read for AgeDecryptor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl super::Decryptor for AgeDecryptor {
+    fn finalize(self: Box<Self>) -> Result<Box<dyn TargetReader>, Error> {
+        let AgeDecryptor { mut reader, source } = { *self };
+        // the final chunk is only authenticated once the stream has been read to the end
+        io::copy(&mut reader, &mut io::sink())?;
+        drop(reader);
+        let mutex = match sync::Arc::try_unwrap(source) {
+            Ok(m) => m,
+            Err(_) => panic!("failed to unwrap arc"),
+        };
+        let source = mutex.into_inner().expect("mutex lock poisoned");
+
+        Ok(source)
+    }
+}
@@ -1,7 +1,7 @@
 
 use std::io;
 
-use crate::destination::Target;
+use crate::destination::{Target, TargetReader};
 
 use anyhow::Error;
 
@@ -31,3 +31,25 @@ impl super::Cryptor for IdentityCryptor {
     }
 }
 
+
+pub struct IdentityDecryptor {
+    inner: Box<TargetReader>,
+}
+
+impl IdentityDecryptor {
+    pub fn new(r: Box<TargetReader>) -> IdentityDecryptor {
+        IdentityDecryptor { inner: r }
+    }
+}
+
+impl io::Read for IdentityDecryptor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl super::Decryptor for IdentityDecryptor {
+    fn finalize(self: Box<Self>) -> Result<Box<TargetReader>, Error> {
+        Ok(self.inner)
+    }
+}
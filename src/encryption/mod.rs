@@ -1,12 +1,66 @@
 pub(crate) mod identity;
 pub(crate) mod pgp;
+pub(crate) mod age;
+pub(crate) mod symmetric;
 
 use std::io;
+use std::sync;
 
-use crate::destination::Target;
+use crate::config;
+use crate::destination::{Target, TargetReader};
 
 use anyhow::Error;
 
+/// The encryption a backup was written with, recorded in its object names.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum EncryptionKind {
+    Pgp,
+    Age,
+    Symmetric,
+}
+
+impl EncryptionKind {
+    pub fn of(typ: &config::EncryptionType) -> EncryptionKind {
+        match typ {
+            config::EncryptionType::Pgp { .. } => EncryptionKind::Pgp,
+            config::EncryptionType::Age { .. } => EncryptionKind::Age,
+            config::EncryptionType::Symmetric { .. } => EncryptionKind::Symmetric,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            EncryptionKind::Pgp => "pgp",
+            EncryptionKind::Age => "age",
+            EncryptionKind::Symmetric => "sym",
+        }
+    }
+
+    pub fn from_extension(ext: &str) -> Option<EncryptionKind> {
+        match ext {
+            "pgp" => Some(EncryptionKind::Pgp),
+            "age" => Some(EncryptionKind::Age),
+            "sym" => Some(EncryptionKind::Symmetric),
+            _ => None,
+        }
+    }
+}
+
 pub trait Cryptor: io::Write {
     fn finalize(self: Box<Self>) -> Result<Box<dyn Target>, Error>;
 }
+
+pub trait Decryptor: io::Read {
+    fn finalize(self: Box<Self>) -> Result<Box<dyn TargetReader>, Error>;
+}
+
+/// Shares the underlying reader with a decrypting stream that doesn't hand
+/// it back, so `finalize` can recover it once the stream is dropped.
+pub(crate) struct ReadWrapper(pub(crate) sync::Arc<sync::Mutex<Box<dyn TargetReader>>>);
+
+impl io::Read for ReadWrapper {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut locked = self.0.lock().expect("mutex lock poisoned");
+        locked.read(buf)
+    }
+}
@@ -3,14 +3,20 @@ use std::io;
 use std::sync;
 use std::cell;
 
-use openpgp::packet::{Key, key::KeyParts, key::KeyRole};
+use openpgp::packet::{Key, key::KeyParts, key::KeyRole, PKESK, SKESK};
 use openpgp::parse::Parse;
-use openpgp::serialize::stream::{Message, Encryptor, LiteralWriter, Recipient};
+use openpgp::parse::stream::{self, DecryptorBuilder, DecryptionHelper, VerificationHelper, MessageLayer, MessageStructure};
+use openpgp::serialize::stream::{Message, Encryptor, LiteralWriter, Recipient, Signer};
 use openpgp::parse::PacketParser;
 use openpgp::cert::{Cert, CertParser};
+use openpgp::crypto::{KeyPair, SessionKey};
 use openpgp::policy::{Policy, StandardPolicy};
+use openpgp::types::SymmetricAlgorithm;
+use openpgp::{Fingerprint, KeyHandle};
 
-use crate::destination::Target;
+use crate::destination::{Target, TargetReader};
+
+use super::ReadWrapper;
 
 use anyhow::{Error, Context};
 
@@ -23,26 +29,73 @@ pub struct PgpCryptor<'a> {
 }
 
 pub struct PgpContext {
-    cert: Cert,
+    certs: Vec<Cert>,
+    signer: Option<Cert>,
     policy: Box<dyn Policy>,
 }
 
 impl PgpContext {
-    pub fn new(key_file: &str) -> Result<PgpContext, Error> {
+    pub fn new(key_files: &[String], signing_key_file: Option<&str>) -> Result<PgpContext, Error> {
         let policy = StandardPolicy::new();
-        let cert = CertParser::from_file(key_file)
-            .context("failed to initialize certificate parser file")?
-            .next()
-            .ok_or_else(|| Error::msg("no public keys found in file"))
-            .and_then(|x| x)?;
+        let mut certs = Vec::new();
+
+        for key_file in key_files {
+            let parsed = read_certs(key_file)?;
+            debug!("read {} certificates from '{}'", parsed.len(), key_file);
+            certs.extend(parsed);
+        }
+
+        if certs.is_empty() {
+            bail!("no public key files configured");
+        }
+
+        let signer = match signing_key_file {
+            Some(f) => Some(read_cert(f)?),
+            None => None,
+        };
 
         Ok(PgpContext {
-            cert: cert,
+            certs: certs,
+            signer: signer,
             policy: Box::new(policy),
         })
     }
 }
 
+fn read_cert(key_file: &str) -> Result<Cert, Error> {
+    CertParser::from_file(key_file)
+        .context(format!("failed to initialize certificate parser for '{}'", key_file))?
+        .next()
+        .ok_or_else(|| format_err!("no keys found in file '{}'", key_file))
+        .and_then(|x| x)
+}
+
+fn read_certs(key_file: &str) -> Result<Vec<Cert>, Error> {
+    let certs = CertParser::from_file(key_file)
+        .context(format!("failed to initialize certificate parser for '{}'", key_file))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if certs.is_empty() {
+        bail!("no keys found in file '{}'", key_file);
+    }
+
+    Ok(certs)
+}
+
+fn signing_keypair(cert: &Cert) -> Result<KeyPair, Error> {
+    let key = cert.keys()
+        .unencrypted_secret()
+        .with_policy(&POLICY, None)
+        .alive()
+        .revoked(false)
+        .for_signing()
+        .next()
+        .ok_or_else(|| format_err!("certificate {} has no usable signing key", cert.fingerprint()))?;
+
+    debug!("signing with key {}", key.key().keyid());
+    Ok(key.key().clone().into_keypair()?)
+}
+
 // struct KeyWrapper<P: KeyParts, R: KeyRole>(Box<Key<P, R>>);
 
 // impl<P, R> From<KeyWrapper<P, R>> for Recipient<'static>
@@ -61,13 +114,28 @@ impl<'a> PgpCryptor<'a> {
 
         //let mut bundle = Vec::new();
 
-        let recipients = ctx.cert.keys()
-            .with_policy(&*ctx.policy, None)
-            .alive()
-            .revoked(false)
-            .for_storage_encryption()
-            // .map(|alg| KeyWrapper(Box::new(alg.key().clone())))
-            .collect::<Vec<_>>();
+        let mut recipients = Vec::new();
+        for cert in &ctx.certs {
+            let keys = cert.keys()
+                .with_policy(&*ctx.policy, None)
+                .alive()
+                .revoked(false)
+                .for_storage_encryption()
+                // .map(|alg| KeyWrapper(Box::new(alg.key().clone())))
+                .collect::<Vec<_>>();
+
+            if keys.is_empty() {
+                warn!("certificate {} has no valid storage encryption keys", cert.fingerprint());
+            }
+
+            recipients.extend(keys);
+        }
+
+        if recipients.is_empty() {
+            bail!("no valid storage encryption keys found in any certificate");
+        }
+
+        debug!("encrypting archive to {} keys of {} certificates", recipients.len(), ctx.certs.len());
 
         // for key_amalg in recipients {
         //     let key = key_amalg.key().clone();
@@ -78,9 +146,13 @@ impl<'a> PgpCryptor<'a> {
         //     .context("failed to read backup encryption key")?;
 
         let message = Message::new(Wrapper(target.clone()));
-        let encryptor = Encryptor::for_recipients(message, recipients).build()
+        let mut message = Encryptor::for_recipients(message, recipients).build()
             .context("failed to initialize encryptor")?;
-        let writer = LiteralWriter::new(encryptor).build()
+        if let Some(ref cert) = ctx.signer {
+            message = Signer::new(message, signing_keypair(cert)?).build()
+                .context("failed to initialize signer")?;
+        }
+        let writer = LiteralWriter::new(message).build()
             .context("failed to initialize writer")?;
 
         Ok(PgpCryptor {
@@ -128,3 +200,141 @@ impl<'a> super::Cryptor for PgpCryptor<'a> {
         Ok(target)
     }
 }
+
+static POLICY: StandardPolicy = StandardPolicy::new();
+
+pub struct PgpDecryptor {
+    source: sync::Arc<sync::Mutex<Box<dyn TargetReader>>>,
+    reader: stream::Decryptor<'static, SecretKeyHelper>,
+}
+
+impl PgpDecryptor {
+    pub fn new(r: Box<dyn TargetReader>, key_file: &str, verification_key_file: Option<&str>) -> Result<PgpDecryptor, Error> {
+        let certs = read_certs(key_file)?;
+
+        let verification = match verification_key_file {
+            Some(f) => Some(read_certs(f)?),
+            None => None,
+        };
+
+        let source = sync::Arc::new(sync::Mutex::new(r));
+        let helper = SecretKeyHelper { certs: certs, verification: SignatureHelper::new(verification) };
+
+        let reader = DecryptorBuilder::from_reader(ReadWrapper(source.clone()))
+            .context("failed to initialize message parser")?
+            .with_policy(&POLICY, None, helper)
+            .context("failed to initialize decryptor")?;
+
+        Ok(PgpDecryptor {
+            source: source,
+            reader: reader,
+        })
+    }
+}
+
+struct SecretKeyHelper {
+    certs: Vec<Cert>,
+    verification: SignatureHelper,
+}
+
+impl VerificationHelper for SecretKeyHelper {
+    fn get_certs(&mut self, ids: &[KeyHandle]) -> openpgp::Result<Vec<Cert>> {
+        self.verification.get_certs(ids)
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> openpgp::Result<()> {
+        self.verification.check(structure)
+    }
+}
+
+/// Requires at least one good signature from the trusted certificates, or
+/// accepts anything if no certificates are configured.
+struct SignatureHelper {
+    certs: Option<Vec<Cert>>,
+}
+
+impl SignatureHelper {
+    fn new(certs: Option<Vec<Cert>>) -> SignatureHelper {
+        SignatureHelper { certs: certs }
+    }
+}
+
+impl VerificationHelper for SignatureHelper {
+    fn get_certs(&mut self, _: &[KeyHandle]) -> openpgp::Result<Vec<Cert>> {
+        Ok(self.certs.clone().unwrap_or_default())
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> openpgp::Result<()> {
+        if self.certs.is_none() {
+            return Ok(());
+        }
+
+        for layer in structure.into_iter() {
+            if let MessageLayer::SignatureGroup { results } = layer {
+                for result in results {
+                    match result {
+                        Ok(good) => {
+                            info!("good signature from key {}", good.ka.key().keyid());
+                            return Ok(());
+                        },
+                        Err(e) => warn!("bad signature: {}", e),
+                    }
+                }
+            }
+        }
+
+        Err(format_err!("no valid signature from a trusted key found"))
+    }
+}
+
+impl DecryptionHelper for SecretKeyHelper {
+    fn decrypt<D>(&mut self, pkesks: &[PKESK], _: &[SKESK], sym_algo: Option<SymmetricAlgorithm>, mut decrypt: D)
+        -> openpgp::Result<Option<Fingerprint>>
+        where D: FnMut(SymmetricAlgorithm, &SessionKey) -> bool
+    {
+        for cert in &self.certs {
+            let keys = cert.keys()
+                .unencrypted_secret()
+                .with_policy(&POLICY, None)
+                .for_storage_encryption();
+
+            for key in keys {
+                let mut pair = key.key().clone().into_keypair()?;
+                let key_id = key.key().keyid();
+
+                for pkesk in pkesks.iter().filter(|p| *p.recipient() == key_id) {
+                    if let Some((algo, session_key)) = pkesk.decrypt(&mut pair, sym_algo) {
+                        if decrypt(algo, &session_key) {
+                            info!("archive decrypted with key {} of certificate {}", key_id, cert.fingerprint());
+                            return Ok(Some(cert.fingerprint()));
+                        }
+                    }
+                }
+            }
+        }
+
+        Err(format_err!("no usable secret key found to decrypt archive"))
+    }
+}
+
+impl io::Read for PgpDecryptor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl super::Decryptor for PgpDecryptor {
+    fn finalize(self: Box<Self>) -> Result<Box<dyn TargetReader>, Error> {
+        let PgpDecryptor { mut reader, source } = { *self };
+        // the integrity check happens once the message has been read to the end
+        io::copy(&mut reader, &mut io::sink())?;
+        drop(reader);
+        let mutex = match sync::Arc::try_unwrap(source) {
+            Ok(m) => m,
+            Err(_) => panic!("failed to unwrap arc"),
+        };
+        let source = mutex.into_inner().expect("mutex lock poisoned");
+
+        Ok(source)
+    }
+}
@@ -0,0 +1,538 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::sync;
+
+use crate::destination::{Target, TargetReader};
+
+use super::ReadWrapper;
+
+use anyhow::{Error, Context};
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+
+use rand::Rng;
+use rand::rngs::OsRng;
+
+use secrecy::{ExposeSecret, SecretString};
+
+use sha2::{Digest, Sha256};
+
+use zeroize::Zeroizing;
+
+const MAGIC_LENGTH: usize = 8;
+const MAGIC: &[u8; MAGIC_LENGTH] = b"BMSYM\x00\x00\x02";
+const SALT_LENGTH: usize = 32;
+const NONCE_PREFIX_LENGTH: usize = 7;
+const HEADER_LENGTH: usize = MAGIC_LENGTH + SALT_LENGTH + NONCE_PREFIX_LENGTH;
+const KEY_LENGTH: usize = 32;
+const TAG_LENGTH: usize = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+const ENCRYPTED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_LENGTH;
+
+/// The secret the stream key is derived from.
+pub enum Secret {
+    /// A passphrase. Streams are encrypted with age, which stretches the
+    /// passphrase with scrypt.
+    Passphrase(SecretString),
+    /// The contents of a key file, which is expected to be random data and
+    /// is only hashed with the salt.
+    KeyFile(Zeroizing<Vec<u8>>),
+}
+
+impl Secret {
+    pub fn load(passphrase_file: Option<&str>, key_file: Option<&str>) -> Result<Secret, Error> {
+        match (passphrase_file, key_file) {
+            (Some(p), None) => Secret::read_passphrase_file(p),
+            (None, Some(k)) => Secret::read_key_file(k),
+            _ => Err(format_err!("exactly one of passphrase_file and key_file must be configured")),
+        }
+    }
+
+    pub fn read_passphrase_file(path: &str) -> Result<Secret, Error> {
+        let data = Zeroizing::new(fs::read_to_string(path)
+            .context(format!("failed to read passphrase file '{}'", path))?);
+        let passphrase = data.trim_end_matches(|c| c == '\n' || c == '\r');
+        if passphrase.is_empty() {
+            bail!("passphrase file '{}' is empty", path);
+        }
+        Ok(Secret::Passphrase(SecretString::new(passphrase.to_string())))
+    }
+
+    pub fn read_key_file(path: &str) -> Result<Secret, Error> {
+        let data = Zeroizing::new(fs::read(path)
+            .context(format!("failed to read key file '{}'", path))?);
+        if data.len() < KEY_LENGTH {
+            bail!("key file '{}' must contain at least {} bytes", path, KEY_LENGTH);
+        }
+        Ok(Secret::KeyFile(data))
+    }
+}
+
+/// Header of a stream encrypted with a key file. The key file is random
+/// data, so no key stretching parameters are needed.
+struct Header {
+    salt: [u8; SALT_LENGTH],
+    nonce_prefix: [u8; NONCE_PREFIX_LENGTH],
+}
+
+impl Header {
+    fn generate() -> Header {
+        Header {
+            salt: OsRng.gen(),
+            nonce_prefix: OsRng.gen(),
+        }
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_LENGTH);
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&self.salt);
+        data.extend_from_slice(&self.nonce_prefix);
+        data
+    }
+
+    fn deserialize(data: &[u8; HEADER_LENGTH]) -> Result<Header, Error> {
+        if &data[..MAGIC_LENGTH] != MAGIC {
+            bail!("data is not a stream encrypted with a key file");
+        }
+
+        let data = &data[MAGIC_LENGTH..];
+        let mut salt = [0; SALT_LENGTH];
+        salt.copy_from_slice(&data[..SALT_LENGTH]);
+        let mut nonce_prefix = [0; NONCE_PREFIX_LENGTH];
+        nonce_prefix.copy_from_slice(&data[SALT_LENGTH..]);
+
+        Ok(Header {
+            salt: salt,
+            nonce_prefix: nonce_prefix,
+        })
+    }
+
+    fn derive_key(&self, key_file: &[u8]) -> Zeroizing<[u8; KEY_LENGTH]> {
+        let mut hasher = Sha256::new();
+        hasher.input(&self.salt);
+        hasher.input(key_file);
+
+        let mut key = Zeroizing::new([0; KEY_LENGTH]);
+        key.copy_from_slice(&hasher.result());
+        key
+    }
+}
+
+/// Nonce of the STREAM construction: a random prefix, the chunk counter and
+/// a flag marking the last chunk, so chunks can't be reordered, dropped or
+/// truncated without failing authentication.
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LENGTH], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[..NONCE_PREFIX_LENGTH].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LENGTH..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+fn crypto_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub struct StreamWriter<W> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    header: Vec<u8>,
+    nonce_prefix: [u8; NONCE_PREFIX_LENGTH],
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> StreamWriter<W> {
+    pub fn new(mut inner: W, key_file: &[u8]) -> Result<StreamWriter<W>, Error> {
+        let header = Header::generate();
+        let key = header.derive_key(key_file);
+        let data = header.serialize();
+        inner.write_all(&data)?;
+
+        Ok(StreamWriter {
+            inner: inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key[..])),
+            header: data,
+            nonce_prefix: header.nonce_prefix,
+            counter: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    fn write_chunk(&mut self, last: bool) -> io::Result<()> {
+        let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+        let payload = Payload { msg: &self.buffer, aad: &self.header };
+        let chunk = self.cipher.encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| crypto_error("failed to encrypt chunk"))?;
+
+        self.counter = self.counter.checked_add(1)
+            .ok_or_else(|| crypto_error("stream is too long"))?;
+        self.buffer.clear();
+        self.inner.write_all(&chunk)
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for StreamWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // only write a full chunk once more data follows, the last chunk is
+        // written by finish
+        if self.buffer.len() == CHUNK_SIZE {
+            self.write_chunk(false)?;
+        }
+
+        let len = std::cmp::min(buf.len(), CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct StreamReader<R> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    header: Vec<u8>,
+    nonce_prefix: [u8; NONCE_PREFIX_LENGTH],
+    counter: u32,
+    chunk: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<R: Read> StreamReader<R> {
+    pub fn new(mut inner: R, key_file: &[u8]) -> Result<StreamReader<R>, Error> {
+        let mut data = [0; HEADER_LENGTH];
+        inner.read_exact(&mut data)
+            .context("failed to read stream header")?;
+        let header = Header::deserialize(&data)?;
+        let key = header.derive_key(key_file);
+
+        Ok(StreamReader {
+            inner: inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key[..])),
+            header: data.to_vec(),
+            nonce_prefix: header.nonce_prefix,
+            counter: 0,
+            chunk: Vec::new(),
+            pos: 0,
+            finished: false,
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let mut data = vec![0; ENCRYPTED_CHUNK_SIZE];
+        let mut len = 0;
+        while len < data.len() {
+            match self.inner.read(&mut data[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        data.truncate(len);
+
+        if len < TAG_LENGTH {
+            return Err(crypto_error("encrypted stream is truncated"));
+        }
+
+        // a full chunk is either followed by more chunks or is the last one
+        let attempts: &[bool] = if len == ENCRYPTED_CHUNK_SIZE { &[false, true] } else { &[true] };
+
+        for &last in attempts {
+            let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+            let payload = Payload { msg: &data, aad: &self.header };
+            if let Ok(plaintext) = self.cipher.decrypt(Nonce::from_slice(&nonce), payload) {
+                self.chunk = plaintext;
+                self.pos = 0;
+                self.counter = self.counter.checked_add(1)
+                    .ok_or_else(|| crypto_error("stream is too long"))?;
+
+                if last {
+                    let mut trailing = [0; 1];
+                    if self.inner.read(&mut trailing)? != 0 {
+                        return Err(crypto_error("unexpected data after the last chunk"));
+                    }
+                    self.finished = true;
+                }
+
+                return Ok(());
+            }
+        }
+
+        Err(crypto_error("failed to authenticate chunk, the data is corrupt or the key is wrong"))
+    }
+}
+
+impl<R: Read> Read for StreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+
+        let len = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+enum Writer {
+    Passphrase(age::stream::StreamWriter<Box<dyn Target>>),
+    KeyFile(StreamWriter<Box<dyn Target>>),
+}
+
+pub struct SymmetricCryptor {
+    writer: Writer,
+}
+
+impl SymmetricCryptor {
+    pub fn new(w: Box<dyn Target>, secret: &Secret) -> Result<SymmetricCryptor, Error> {
+        let writer = match secret {
+            Secret::Passphrase(passphrase) => {
+                let passphrase = SecretString::new(passphrase.expose_secret().clone());
+                let writer = age::Encryptor::with_user_passphrase(passphrase)
+                    .wrap_output(w)
+                    .context("failed to initialize encryptor")?;
+                Writer::Passphrase(writer)
+            },
+            Secret::KeyFile(key_file) => {
+                let writer = StreamWriter::new(w, key_file)
+                    .context("failed to initialize encryptor")?;
+                Writer::KeyFile(writer)
+            },
+        };
+
+        Ok(SymmetricCryptor { writer: writer })
+    }
+}
+
+impl io::Write for SymmetricCryptor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.writer {
+            Writer::Passphrase(ref mut w) => w.write(buf),
+            Writer::KeyFile(ref mut w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer {
+            Writer::Passphrase(ref mut w) => w.flush(),
+            Writer::KeyFile(ref mut w) => w.flush(),
+        }
+    }
+}
+
+impl super::Cryptor for SymmetricCryptor {
+    fn finalize(self: Box<Self>) -> Result<Box<dyn Target>, Error> {
+        let target = match self.writer {
+            Writer::Passphrase(w) => w.finish()?,
+            Writer::KeyFile(w) => w.finish()?,
+        };
+        Ok(target)
+    }
+}
+
+enum Reader {
+    Passphrase {
+        source: sync::Arc<sync::Mutex<Box<dyn TargetReader>>>,
+        reader: age::stream::StreamReader<ReadWrapper>,
+    },
+    KeyFile(StreamReader<Box<dyn TargetReader>>),
+}
+
+pub struct SymmetricDecryptor {
+    reader: Reader,
+}
+
+impl SymmetricDecryptor {
+    /// `max_work_factor` limits the scrypt cost a passphrase encrypted stream
+    /// may ask for, as log2 of N. Without it age allows about 16 seconds of
+    /// work on this machine.
+    pub fn new(r: Box<dyn TargetReader>, secret: &Secret, max_work_factor: Option<u8>) -> Result<SymmetricDecryptor, Error> {
+        let reader = match secret {
+            Secret::Passphrase(passphrase) => {
+                let source = sync::Arc::new(sync::Mutex::new(r));
+                let decryptor = match age::Decryptor::new(ReadWrapper(source.clone()))
+                    .context("failed to parse age header")?
+                {
+                    age::Decryptor::Passphrase(d) => d,
+                    age::Decryptor::Recipients(_) => bail!("stream is encrypted to recipients, not with a passphrase"),
+                };
+
+                let reader = decryptor.decrypt(passphrase, max_work_factor)
+                    .context("failed to decrypt stream with the passphrase")?;
+                Reader::Passphrase { source: source, reader: reader }
+            },
+            Secret::KeyFile(key_file) => {
+                let reader = StreamReader::new(r, key_file)
+                    .context("failed to initialize decryptor")?;
+                Reader::KeyFile(reader)
+            },
+        };
+
+        Ok(SymmetricDecryptor { reader: reader })
+    }
+}
+
+impl io::Read for SymmetricDecryptor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.reader {
+            Reader::Passphrase { ref mut reader, .. } => reader.read(buf),
+            Reader::KeyFile(ref mut r) => r.read(buf),
+        }
+    }
+}
+
+impl super::Decryptor for SymmetricDecryptor {
+    fn finalize(self: Box<Self>) -> Result<Box<dyn TargetReader>, Error> {
+        match self.reader {
+            Reader::Passphrase { mut reader, source } => {
+                // the final chunk is only authenticated once the stream has been read to the end
+                io::copy(&mut reader, &mut io::sink())?;
+                drop(reader);
+                let mutex = match sync::Arc::try_unwrap(source) {
+                    Ok(m) => m,
+                    Err(_) => panic!("failed to unwrap arc"),
+                };
+                Ok(mutex.into_inner().expect("mutex lock poisoned"))
+            },
+            Reader::KeyFile(mut reader) => {
+                // the last chunk proves the stream wasn't truncated
+                io::copy(&mut reader, &mut io::sink())?;
+                if !reader.is_finished() {
+                    bail!("encrypted stream is truncated");
+                }
+                Ok(reader.into_inner())
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    use crate::destination::{Destination, TargetDescriptor, TargetType};
+    use crate::destination::dir::DirectoryDestination;
+    use crate::encryption::{Cryptor, Decryptor};
+
+    use chrono::prelude::*;
+
+    use tempfile::TempDir;
+
+    fn encrypt(key_file: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut writer = StreamWriter::new(Vec::new(), key_file).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(key_file: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut reader = StreamReader::new(data, key_file).unwrap();
+        let mut result = Vec::new();
+        reader.read_to_end(&mut result)?;
+        if !reader.is_finished() {
+            return Err(crypto_error("not finished"));
+        }
+        Ok(result)
+    }
+
+    fn key_file() -> Vec<u8> {
+        (0..KEY_LENGTH as u8).collect()
+    }
+
+    fn passphrase(p: &str) -> Secret {
+        Secret::Passphrase(SecretString::new(p.to_string()))
+    }
+
+    fn encrypt_with(destination: &DirectoryDestination, desc: &TargetDescriptor, secret: &Secret, data: &[u8]) {
+        let target = destination.allocate(desc, 0).unwrap();
+        let mut cryptor = Box::new(SymmetricCryptor::new(target, secret).unwrap());
+        cryptor.write_all(data).unwrap();
+        cryptor.finalize().unwrap().finalize().unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        for &len in &[0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE] {
+            let data = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let encrypted = encrypt(&key_file(), &data);
+            assert_eq!(decrypt(&key_file(), &encrypted).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn round_trip_passphrase() {
+        let dir = TempDir::new().unwrap();
+        let destination = DirectoryDestination::new(dir.path());
+        let desc = TargetDescriptor::new("host", "job", Utc.timestamp(1500000000, 0), TargetType::Full);
+        let secret = passphrase("correct horse battery staple");
+
+        encrypt_with(&destination, &desc, &secret, b"data");
+
+        let reader = destination.open(&desc).unwrap();
+        let mut decryptor = Box::new(SymmetricDecryptor::new(reader, &secret, None).unwrap());
+        let mut result = Vec::new();
+        decryptor.read_to_end(&mut result).unwrap();
+        decryptor.finalize().unwrap();
+        assert_eq!(result, b"data");
+
+        let reader = destination.open(&desc).unwrap();
+        assert!(SymmetricDecryptor::new(reader, &passphrase("wrong"), None).is_err());
+
+        // age never picks a work factor this low
+        let reader = destination.open(&desc).unwrap();
+        assert!(SymmetricDecryptor::new(reader, &secret, Some(1)).is_err());
+    }
+
+    #[test]
+    fn wrong_key_file_fails() {
+        let encrypted = encrypt(&key_file(), b"data");
+        assert!(decrypt(&[7; KEY_LENGTH], &encrypted).is_err());
+    }
+
+    #[test]
+    fn modified_data_fails() {
+        let mut encrypted = encrypt(&key_file(), b"data");
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        assert!(decrypt(&key_file(), &encrypted).is_err());
+    }
+
+    #[test]
+    fn truncated_stream_fails() {
+        let data = vec![0; 2 * CHUNK_SIZE + 10];
+        let encrypted = encrypt(&key_file(), &data);
+        let truncated = &encrypted[..HEADER_LENGTH + ENCRYPTED_CHUNK_SIZE];
+        assert!(decrypt(&key_file(), truncated).is_err());
+    }
+
+    #[test]
+    fn old_header_is_rejected() {
+        let mut encrypted = encrypt(&key_file(), b"data");
+        encrypted[MAGIC_LENGTH - 1] = 1;
+        assert!(StreamReader::new(&encrypted[..], &key_file()).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use super::backup::{Job, fetch_manifest, load_job};
+use super::config;
+use super::destination::{Destination, BackupSearchRequest, TargetType};
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::Error;
+
+use chrono::prelude::*;
+
+#[derive(Debug, Clone, Copy)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OutputFormat, Error> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format_err!("invalid output format '{}'", s)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Listing {
+    host: String,
+    job: String,
+    timestamp: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    size: Option<u64>,
+    entries: Option<usize>,
+    storage_class: Option<String>,
+}
+
+/// Lists backups in a destination. Manifests written through a compression
+/// or encryption pipeline are decoded with the config of the job of the same
+/// name, backups of jobs missing from the config show no entry count.
+pub fn list(config: &config::Config, destination: &dyn Destination, request: &BackupSearchRequest, format: OutputFormat) -> Result<(), Error> {
+    let mut backups = destination.list_backups(request)?;
+    backups.sort_by(|a, b| {
+        (a.host(), a.job(), a.timestamp()).cmp(&(b.host(), b.job(), b.timestamp()))
+    });
+
+    let mut jobs: HashMap<String, Option<Job>> = HashMap::new();
+    for desc in &backups {
+        if !jobs.contains_key(desc.job()) {
+            let job = match load_job(config, desc.job()) {
+                Ok(j) => Some(j),
+                Err(e) => {
+                    debug!("no usable config for job {}: {}", desc.job(), e);
+                    None
+                },
+            };
+            jobs.insert(desc.job().to_string(), job);
+        }
+    }
+
+    let listings = backups.iter().map(|desc| {
+        let info = match destination.stat(desc) {
+            Ok(i) => Some(i),
+            Err(e) => {
+                warn!("failed to read data object of backup from {}: {}", desc.timestamp(), e);
+                None
+            },
+        };
+
+        let job = jobs.get(desc.job()).and_then(|j| j.as_ref());
+        let entries = match fetch_manifest(destination, desc, job) {
+            Ok(m) => Some(m.len()),
+            Err(e) => {
+                warn!("failed to read manifest of backup from {}: {}", desc.timestamp(), e);
+                None
+            },
+        };
+
+        Listing {
+            host: desc.host().into(),
+            job: desc.job().into(),
+            timestamp: desc.timestamp().to_rfc3339_opts(SecondsFormat::Secs, true),
+            kind: type_name(desc.kind()),
+            size: info.as_ref().map(|i| i.size()),
+            entries: entries,
+            storage_class: info.as_ref().and_then(|i| i.storage_class().map(String::from)),
+        }
+    })
+    .collect::<Vec<_>>();
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&listings)?),
+        OutputFormat::Table => print_table(&listings),
+    }
+
+    Ok(())
+}
+
+fn type_name(kind: TargetType) -> &'static str {
+    match kind {
+        TargetType::Full => "full",
+        TargetType::Differential => "differential",
+        TargetType::Incremental => "incremental",
+    }
+}
+
+fn print_table(listings: &[Listing]) {
+    let host_width = listings.iter().map(|l| l.host.len()).fold(4, usize::max);
+    let job_width = listings.iter().map(|l| l.job.len()).fold(3, usize::max);
+
+    println!("{:<hw$}  {:<jw$}  {:<20}  {:<12}  {:>15}  {:>8}  {}",
+        "HOST", "JOB", "TIMESTAMP", "TYPE", "SIZE", "ENTRIES", "STORAGE CLASS",
+        hw = host_width, jw = job_width);
+
+    for l in listings {
+        let size = l.size.map_or_else(|| "-".into(), |s| s.to_string());
+        let entries = l.entries.map_or_else(|| "-".into(), |e| e.to_string());
+        let storage_class = l.storage_class.as_ref().map_or("-", |s| s.as_str());
+
+        println!("{:<hw$}  {:<jw$}  {:<20}  {:<12}  {:>15}  {:>8}  {}",
+            l.host, l.job, l.timestamp, l.kind, size, entries, storage_class,
+            hw = host_width, jw = job_width);
+    }
+}
@@ -6,6 +6,7 @@ extern crate env_logger;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate toml;
 #[macro_use]
 extern crate anyhow;
@@ -19,7 +20,14 @@ extern crate futures;
 extern crate tar;
 extern crate crossbeam;
 extern crate flate2;
+extern crate zstd;
+extern crate xz2;
+extern crate lz4;
 extern crate sequoia_openpgp as openpgp;
+extern crate age;
+extern crate chacha20poly1305;
+extern crate secrecy;
+extern crate zeroize;
 extern crate chrono;
 extern crate gethostname;
 extern crate nix;
@@ -31,6 +39,9 @@ extern crate sha2;
 extern crate bincode;
 extern crate cron;
 extern crate bytes;
+extern crate signal_hook;
+extern crate ignore;
+extern crate xattr;
 
 mod mount;
 mod config;
@@ -39,22 +50,79 @@ mod compression;
 mod encryption;
 mod destination;
 mod backup;
+mod restore;
+mod list;
+mod prune;
+mod daemon;
 mod stat;
 mod manifest;
+mod xattrs;
+mod sparse;
 
 use std::path;
+use std::time;
 
 use structopt::StructOpt;
 
+use chrono::prelude::*;
+
 use anyhow::Error;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "backupmanager", about = "a file backup program")]
 struct Opt {
-    #[structopt(short = "j", long = "job")]
-    job: String,
     #[structopt(short = "c", long = "config", default_value = "/etc/backupmanager/config.toml")]
     config: path::PathBuf,
+    #[structopt(short = "j", long = "job", help = "job to run when no command is given, same as `backup -j`")]
+    job: Option<String>,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(name = "backup", about = "run a backup job")]
+    Backup {
+        #[structopt(short = "j", long = "job", help = "job to run, can be given multiple times")]
+        jobs: Vec<String>,
+        #[structopt(short = "a", long = "all", help = "run all configured jobs")]
+        all: bool,
+        #[structopt(short = "t", long = "tag", help = "run all jobs with this tag, can be given multiple times")]
+        tags: Vec<String>,
+    },
+    #[structopt(name = "restore", about = "restore a backup into a directory")]
+    Restore {
+        #[structopt(short = "j", long = "job")]
+        job: String,
+        #[structopt(long = "host", help = "host that created the backup, defaults to this host")]
+        host: Option<String>,
+        #[structopt(short = "t", long = "time", help = "timestamp of the backup, defaults to the latest")]
+        timestamp: Option<DateTime<Utc>>,
+        #[structopt(short = "o", long = "target")]
+        target: path::PathBuf,
+    },
+    #[structopt(name = "list", about = "list the backups stored in a destination")]
+    List {
+        #[structopt(short = "d", long = "destination")]
+        destination: String,
+        #[structopt(long = "host", help = "only list backups of this host")]
+        host: Option<String>,
+        #[structopt(short = "j", long = "job", help = "only list backups of this job")]
+        job: Option<String>,
+        #[structopt(short = "f", long = "format", default_value = "table", help = "output format, table or json")]
+        format: list::OutputFormat,
+    },
+    #[structopt(name = "prune", about = "delete backups according to the job's retention policy")]
+    Prune {
+        #[structopt(short = "j", long = "job")]
+        job: String,
+        #[structopt(long = "host", help = "host that created the backups, defaults to this host")]
+        host: Option<String>,
+        #[structopt(short = "n", long = "dry-run", help = "only show which backups would be deleted")]
+        dry_run: bool,
+    },
+    #[structopt(name = "daemon", about = "run jobs according to their schedules, SIGHUP reloads the config")]
+    Daemon,
 }
 
 fn main() -> Result<(), Error> {
@@ -64,59 +132,103 @@ fn main() -> Result<(), Error> {
 
     let config = config::load_config(&opt.config)?;
 
-    let jobs = config.jobs
+    // `backupmanager -j <job>` predates the subcommands and stays supported
+    let cmd = match (opt.cmd, opt.job) {
+        (Some(cmd), None) => cmd,
+        (None, Some(job)) => Command::Backup { jobs: vec![job], all: false, tags: Vec::new() },
+        (Some(_), Some(_)) => bail!("--job without a command is short for `backup --job`, pass it after the command instead"),
+        (None, None) => bail!("no command given, see --help"),
+    };
+
+    match cmd {
+        Command::Backup { jobs, all, tags } => {
+            let names = select_jobs(&config, &jobs, all, &tags)?;
+            run_jobs(&config, &names)?;
+        },
+        Command::Restore { job, host, timestamp, target } => {
+            let job = backup::load_job(&config, &job)?;
+            let host = resolve_host(host)?;
+            restore::restore(&job, &host, timestamp, &target)?;
+        },
+        Command::List { destination, host, job, format } => {
+            let dest = config.destinations.as_ref()
+                .ok_or_else(|| format_err!("no destination configs found"))?
+                .iter()
+                .find(|d| d.name == destination)
+                .ok_or_else(|| format_err!("destination {} not found", destination))?;
+            let destination = backup::build_destination(dest)?;
+            let request = destination::BackupSearchRequest::filter(host, job);
+            list::list(&config, destination.as_ref(), &request, format)?;
+        },
+        Command::Prune { job, host, dry_run } => {
+            let job = backup::load_job(&config, &job)?;
+            let host = resolve_host(host)?;
+            prune::prune(&job, &host, dry_run)?;
+        },
+        Command::Daemon => {
+            daemon::run(&opt.config, config)?;
+        },
+    }
+
+    Ok(())
+}
+
+fn resolve_host(host: Option<String>) -> Result<String, Error> {
+    match host {
+        Some(h) => Ok(h),
+        None => gethostname::gethostname().into_string()
+            .map_err(|_| format_err!("failed to convert hostname to string")),
+    }
+}
+
+fn select_jobs(config: &config::Config, names: &[String], all: bool, tags: &[String]) -> Result<Vec<String>, Error> {
+    let jobs = config.jobs.as_ref()
         .ok_or_else(|| format_err!("no job configs found"))?;
 
-    let job = jobs.into_iter()
-        .find(|j| j.name == opt.job)
-        .ok_or_else(|| format_err!("backup job {} not found", opt.job))?;
-
-    let sources = config.sources
-        .ok_or_else(|| format_err!("no source configs found"))?;
-    let src = sources.into_iter()
-        .find(|s| s.name == job.source)
-        .ok_or_else(|| format_err!("source {} not found", job.source))?;
-
-    let destinations = config.destinations
-        .ok_or_else(|| format_err!("no destination configs found"))?;
-    let dest = destinations.into_iter()
-        .find(|d| d.name == job.destination)
-        .ok_or_else(|| format_err!("destination {} not found", job.destination))?;
-
-    let comp = match job.compression {
-        None => None,
-        Some(comp) => {
-            let compressions = config.compression
-                .ok_or_else(|| format_err!("no compression configs found"))?;
-            let compression = compressions.into_iter()
-                .find(|c| c.name == comp)
-                .ok_or_else(|| format_err!("compression {} not found", comp))?;
-            Some(compression)
+    for name in names {
+        if !jobs.iter().any(|j| &j.name == name) {
+            bail!("backup job {} not found", name);
         }
-    };
+    }
+
+    let selected = jobs.iter()
+        .filter(|j| all || names.contains(&j.name) || tags.iter().any(|t| j.has_tag(t)))
+        .map(|j| j.name.clone())
+        .collect::<Vec<_>>();
+
+    if selected.is_empty() {
+        bail!("no backup jobs selected, use --job, --tag or --all");
+    }
+
+    Ok(selected)
+}
+
+fn run_jobs(config: &config::Config, names: &[String]) -> Result<(), Error> {
+    let mut results = Vec::new();
 
-    let encr = match job.encryption {
-        None => None,
-        Some(enc) => {
-            let encryptions = config.encryption
-                .ok_or_else(|| format_err!("no encryption configs found"))?;
-            let encryption = encryptions.into_iter()
-                .find(|e| e.name == enc)
-                .ok_or_else(|| format_err!("encryption {} not found", enc))?;
-            Some(encryption)
+    for name in names {
+        info!("starting backup job '{}'", name);
+        let start = time::Instant::now();
+        let result = backup::load_job(config, name).and_then(|job| backup::backup(&job));
+
+        if let Err(ref e) = result {
+            error!("backup job '{}' failed: {:?}", name, e);
         }
-    };
 
-    let job = backup::Job {
-        name: job.name,
-        typ: job.typ,
-        source: src,
-        destination: dest,
-        encryption: encr,
-        compression: comp,
-    };
+        results.push((name, start.elapsed(), result));
+    }
+
+    for (name, elapsed, result) in &results {
+        match result {
+            Ok(()) => println!("{}: succeeded in {}s", name, elapsed.as_secs()),
+            Err(e) => println!("{}: failed after {}s: {}", name, elapsed.as_secs(), e),
+        }
+    }
 
-    backup::backup(&job)?;
+    let failed = results.iter().filter(|r| r.2.is_err()).count();
+    if failed > 0 {
+        bail!("{} of {} backup jobs failed", failed, results.len());
+    }
 
     Ok(())
 }
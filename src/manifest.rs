@@ -1,7 +1,9 @@
 
-use std::collections::BTreeSet;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
 use std::io::{BufRead, BufReader, Read, Write};
 use std::convert::From;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
 use anyhow::Error;
@@ -13,26 +15,53 @@ use sha2::Digest;
 
 use chrono::prelude::*;
 
+use crate::destination::{TargetDescriptor, TargetType};
+
 #[derive(Eq, PartialEq, Debug)]
 pub struct Manifest {
+    algorithm: Algorithm,
     salt: Key,
-    keys: BTreeSet<Key>,
+    records: BTreeMap<Key, Record>,
+    deleted: BTreeMap<Key, Record>,
+    parent: Option<Parent>,
+    obfuscate: bool,
 }
 
 const KEY_LENGTH: usize = 32;
 
 impl Manifest {
-    pub fn new() -> Result<Manifest, Error> {
+    /// Creates an empty manifest in the current format. Obfuscated manifests
+    /// leave out the plain text paths, entries can then only be looked up by
+    /// someone who already knows the path.
+    pub fn new(obfuscate: bool) -> Result<Manifest, Error> {
         let salt_data: [u8; KEY_LENGTH] = OsRng.gen();
 
         Ok(Manifest {
+            algorithm: Algorithm::Sha256Records,
             salt: Key { data: salt_data },
-            keys: BTreeSet::new(),
+            records: BTreeMap::new(),
+            deleted: BTreeMap::new(),
+            parent: None,
+            obfuscate: obfuscate,
         })
     }
 
     pub fn len(&self) -> usize {
-        self.keys.len()
+        self.records.len()
+    }
+
+    pub fn parent(&self) -> Option<&Parent> {
+        self.parent.as_ref()
+    }
+
+    pub fn set_parent(&mut self, desc: &TargetDescriptor) {
+        self.parent = Some(Parent::new(*desc.timestamp(), desc.kind()));
+    }
+
+    /// Uses the salt of the base manifest, so keys and digests of a backup
+    /// chain can be compared directly.
+    pub fn inherit_salt(&mut self, base: &Manifest) {
+        self.salt = Key { data: base.salt.data };
     }
 
     pub fn deserialize<R>(r: R) -> Result<Manifest, Error> 
@@ -49,20 +78,22 @@ impl Manifest {
 
             parts.reverse();
 
-            let _ = Algorithm::from_u32(pop(&mut parts)?.parse()?)?;
+            let algorithm = Algorithm::from_u32(pop(&mut parts)?.parse()?)?;
 
-            let salt = hex::decode(pop(&mut parts)?)?;
+            let salt = parse_key(pop(&mut parts)?)?;
 
-            if salt.len() != KEY_LENGTH {
-                bail!("salt must be {} bytes", KEY_LENGTH)
-            }
-
-            let mut salt_data = [0; KEY_LENGTH];
-            salt_data.copy_from_slice(&salt[..]);
+            let parent = match parts.pop() {
+                None => None,
+                Some(p) => Some(Parent::parse(p)?),
+            };
 
             Manifest {
-                salt: Key { data: salt_data },
-                keys: BTreeSet::new(),
+                algorithm: algorithm,
+                salt: salt,
+                records: BTreeMap::new(),
+                deleted: BTreeMap::new(),
+                parent: parent,
+                obfuscate: true,
             }
         };
 
@@ -72,17 +103,23 @@ impl Manifest {
                 break;
             }
 
-            let hash_data = hex::decode(line.trim())?;
+            let mut fields = line.trim().split(' ');
+            let key = parse_key(fields.next().unwrap_or(""))?;
 
-            if hash_data.len() != KEY_LENGTH {
-                bail!("hash must be {} bytes", KEY_LENGTH);
-            }
+            let record = match manifest.algorithm {
+                Algorithm::Sha256 => Record::default(),
+                Algorithm::Sha256Records => Record::parse(fields)?,
+            };
 
-            let mut key_data = [0; KEY_LENGTH];
-            key_data.copy_from_slice(&hash_data[..]);
+            if record.path.is_some() {
+                manifest.obfuscate = false;
+            }
 
-            let key = Key { data: key_data };
-            manifest.keys.insert(key);
+            if record.deleted {
+                manifest.deleted.insert(key, record);
+            } else {
+                manifest.records.insert(key, record);
+            }
         }
 
         Ok(manifest)
@@ -91,12 +128,18 @@ impl Manifest {
     pub fn serialize<W>(&self, mut w: W) -> Result<(), Error> 
         where W: Write
     {
-        let algo = Algorithm::Sha256.as_u32();
+        let algo = self.algorithm.as_u32();
         let salt = hex::encode(self.salt.data);
-        write!(w, "{} {}\n", algo, salt)?;
-        for key in &self.keys {
+        match self.parent {
+            None => write!(w, "{} {}\n", algo, salt)?,
+            Some(ref p) => write!(w, "{} {} {}\n", algo, salt, p.format())?,
+        };
+        for (key, record) in self.records.iter().chain(self.deleted.iter()) {
             let encoded = hex::encode(key.data);
-            write!(w, "{}\n", encoded)?;
+            match self.algorithm {
+                Algorithm::Sha256 => write!(w, "{}\n", encoded)?,
+                Algorithm::Sha256Records => write!(w, "{}{}\n", encoded, record.format())?,
+            }
         }
 
         Ok(())
@@ -104,19 +147,148 @@ impl Manifest {
 
     pub fn insert(&mut self, e: &Entry) {
         let key = self.gen_key(&e);
-        self.keys.insert(key);
+        let record = match self.algorithm {
+            Algorithm::Sha256 => Record::default(),
+            Algorithm::Sha256Records => Record {
+                meta: Some(self.gen_meta(e)),
+                stat: self.gen_stat(e),
+                size: e.size,
+                offset: e.offset,
+                digest: e.digest.map(|d| Key { data: d }),
+                path: if self.obfuscate { None } else { Some(e.path.clone()) },
+                link: e.link.as_ref().map(|l| self.salted_hash(l.as_os_str().as_bytes())),
+                deleted: false,
+            },
+        };
+        self.records.insert(key, record);
+    }
+
+    /// Records an entry that was left out of the archive because it is
+    /// unchanged since `base`. Carrying these keeps the manifest a complete
+    /// listing of the source, which is what an incremental backup is
+    /// compared against. Requires the salt of `base`.
+    pub fn carry(&mut self, e: &Entry, base: &Manifest) {
+        self.insert(e);
+
+        let key = self.gen_key(e);
+        if let (Some(record), Some(old)) = (self.records.get_mut(&key), base.find(&e.path)) {
+            record.size = old.size;
+            record.digest = old.digest.clone();
+            record.link = old.link.clone();
+        }
+    }
+
+    /// Records every entry of `base` that is not in this manifest as deleted,
+    /// so a restore can remove it again. Requires the salt of `base`, and is
+    /// not possible for old manifests, whose keys include the metadata.
+    pub fn record_deletions(&mut self, base: &Manifest) {
+        if base.algorithm == Algorithm::Sha256 {
+            warn!("base manifest uses an old format, deleted files are not recorded");
+            return;
+        }
+
+        for (key, record) in &base.records {
+            if !self.records.contains_key(key) {
+                let deleted = Record {
+                    path: if self.obfuscate { None } else { record.path.clone() },
+                    deleted: true,
+                    ..Record::default()
+                };
+                self.deleted.insert(key.clone(), deleted);
+            }
+        }
+    }
+
+    pub fn deleted_len(&self) -> usize {
+        self.deleted.len()
+    }
+
+    /// Checks if a path was deleted since the base backup.
+    pub fn is_deleted<P: AsRef<Path>>(&self, path: P) -> bool {
+        match self.algorithm {
+            Algorithm::Sha256 => false,
+            Algorithm::Sha256Records => self.deleted.contains_key(&self.salted_hash(path.as_ref().as_os_str().as_bytes())),
+        }
     }
 
+    /// Checks if the base manifest has an entry with the same path and
+    /// metadata.
     pub fn contains(&self, e: &Entry) -> bool {
         let key = self.gen_key(&e);
-        self.keys.contains(&key)
+        match (&self.algorithm, self.records.get(&key)) {
+            (_, None) => false,
+            (Algorithm::Sha256, Some(_)) => true,
+            (Algorithm::Sha256Records, Some(r)) => r.meta.as_ref() == Some(&self.gen_meta(e)),
+        }
     }
 
-    fn gen_key(&self, e: &Entry) -> Key {
+    /// Like `contains`, but also requires size, inode, ctime and the
+    /// nanoseconds of mtime to match. Records written without these never
+    /// match.
+    pub fn contains_strict(&self, e: &Entry) -> bool {
+        if !self.contains(e) {
+            return false;
+        }
+
+        let recorded = self.records.get(&self.gen_key(e)).and_then(|r| r.stat.as_ref());
+        match (recorded, self.gen_stat(e)) {
+            (Some(stat), Some(ref expected)) => stat == expected,
+            _ => false,
+        }
+    }
+
+    /// Checks if the recorded content digest of a path matches. The digest
+    /// must come from `content_hasher` of this manifest.
+    pub fn content_matches<P: AsRef<Path>>(&self, path: P, digest: &[u8]) -> bool {
+        self.find(path).and_then(|r| r.digest()) == Some(digest)
+    }
+
+    /// Looks up the record of a path, only possible in the current format.
+    pub fn find<P: AsRef<Path>>(&self, path: P) -> Option<&Record> {
+        match self.algorithm {
+            Algorithm::Sha256 => None,
+            Algorithm::Sha256Records => self.records.get(&self.salted_hash(path.as_ref().as_os_str().as_bytes())),
+        }
+    }
+
+    /// Returns a hasher for file contents, salted like the keys so digests
+    /// don't reveal known files.
+    pub fn content_hasher(&self) -> sha2::Sha256 {
         let mut hasher = sha2::Sha256::new();
+        hasher.input(&self.salt.data);
+        hasher
+    }
+
+    fn gen_key(&self, e: &Entry) -> Key {
+        match self.algorithm {
+            Algorithm::Sha256 => {
+                let mut buffer = Vec::new();
+                e.serialize(&mut buffer).expect("failed to write buffer data");
+                self.salted_hash(&buffer)
+            },
+            Algorithm::Sha256Records => self.salted_hash(e.path.as_os_str().as_bytes()),
+        }
+    }
+
+    fn gen_meta(&self, e: &Entry) -> Key {
+        let mut buffer = Vec::new();
+        e.serialize_meta(&mut buffer).expect("failed to write buffer data");
+        self.salted_hash(&buffer)
+    }
+
+    fn gen_stat(&self, e: &Entry) -> Option<Key> {
+        if e.stat.is_none() {
+            return None;
+        }
+
         let mut buffer = Vec::new();
-        e.serialize(&mut buffer).expect("failed to write buffer data");
-        hasher.input(&buffer);
+        e.serialize_stat(&mut buffer).expect("failed to write buffer data");
+        Some(self.salted_hash(&buffer))
+    }
+
+    fn salted_hash(&self, data: &[u8]) -> Key {
+        let mut hasher = sha2::Sha256::new();
+        hasher.input(data);
         hasher.input(&self.salt.data);
         let hash = hasher.result();
         let mut key_data = [0; KEY_LENGTH];
@@ -126,18 +298,184 @@ impl Manifest {
     }
 }
 
+fn parse_key(s: &str) -> Result<Key, Error> {
+    let data = hex::decode(s)?;
+
+    if data.len() != KEY_LENGTH {
+        bail!("hash must be {} bytes", KEY_LENGTH);
+    }
+
+    let mut key_data = [0; KEY_LENGTH];
+    key_data.copy_from_slice(&data[..]);
+
+    Ok(Key { data: key_data })
+}
+
+/// What the manifest knows about an archived entry. Old manifests only
+/// record the key, so all fields are optional.
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct Record {
+    meta: Option<Key>,
+    stat: Option<Key>,
+    size: Option<u64>,
+    offset: Option<u64>,
+    digest: Option<Key>,
+    path: Option<PathBuf>,
+    link: Option<Key>,
+    deleted: bool,
+}
+
+impl Record {
+    /// Size of the file contents.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Offset of the entry's header in the uncompressed tar stream.
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    pub fn digest(&self) -> Option<&[u8]> {
+        self.digest.as_ref().map(|d| &d.data[..])
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_ref().map(|p| p.as_path())
+    }
+
+    /// Key of the entry this one is a hard link to.
+    pub fn link(&self) -> Option<&[u8]> {
+        self.link.as_ref().map(|l| &l.data[..])
+    }
+
+    /// Formats the fields as ` name=value` pairs. Readers skip names they
+    /// don't know, so fields can be added without a new format version.
+    fn format(&self) -> String {
+        let mut line = String::new();
+        if let Some(ref meta) = self.meta {
+            line.push_str(&format!(" meta={}", hex::encode(meta.data)));
+        }
+        if let Some(ref stat) = self.stat {
+            line.push_str(&format!(" stat={}", hex::encode(stat.data)));
+        }
+        if let Some(size) = self.size {
+            line.push_str(&format!(" size={}", size));
+        }
+        if let Some(offset) = self.offset {
+            line.push_str(&format!(" offset={}", offset));
+        }
+        if let Some(ref digest) = self.digest {
+            line.push_str(&format!(" digest={}", hex::encode(digest.data)));
+        }
+        if let Some(ref path) = self.path {
+            line.push_str(&format!(" path={}", hex::encode(path.as_os_str().as_bytes())));
+        }
+        if let Some(ref link) = self.link {
+            line.push_str(&format!(" link={}", hex::encode(link.data)));
+        }
+        if self.deleted {
+            line.push_str(" deleted=1");
+        }
+        line
+    }
+
+    fn parse<'a, I>(fields: I) -> Result<Record, Error>
+        where I: Iterator<Item = &'a str>
+    {
+        let mut record = Record::default();
+
+        for field in fields.filter(|f| !f.is_empty()) {
+            let mut parts = field.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            let value = parts.next()
+                .ok_or_else(|| format_err!("invalid manifest field '{}'", field))?;
+
+            match name {
+                "meta" => record.meta = Some(parse_key(value)?),
+                "stat" => record.stat = Some(parse_key(value)?),
+                "size" => record.size = Some(value.parse()?),
+                "offset" => record.offset = Some(value.parse()?),
+                "digest" => record.digest = Some(parse_key(value)?),
+                "path" => record.path = Some(PathBuf::from(OsStr::from_bytes(&hex::decode(value)?))),
+                "link" => record.link = Some(parse_key(value)?),
+                "deleted" => record.deleted = value == "1",
+                _ => trace!("ignoring unknown manifest field '{}'", name),
+            }
+        }
+
+        Ok(record)
+    }
+}
+
 fn pop<T>(vec: &mut Vec<T>) -> Result<T, Error> 
     where T: std::fmt::Display
 {
     vec.pop().ok_or_else(|| format_err!("not enough values"))
 }
 
+/// The backup a differential was taken against, identified by its timestamp
+/// and kind. Host and job are always the same as the manifest's own.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Parent {
+    timestamp: DateTime<Utc>,
+    kind: TargetType,
+}
+
+impl Parent {
+    pub fn new(timestamp: DateTime<Utc>, kind: TargetType) -> Parent {
+        Parent { timestamp: timestamp, kind: kind }
+    }
+
+    pub fn timestamp(&self) -> &DateTime<Utc> {
+        &self.timestamp
+    }
+
+    pub fn kind(&self) -> TargetType {
+        self.kind
+    }
+
+    pub fn matches(&self, desc: &TargetDescriptor) -> bool {
+        *desc.timestamp() == self.timestamp && desc.kind() == self.kind
+    }
+
+    fn format(&self) -> String {
+        let time = self.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true);
+        format!("{}.{}", time, self.kind.extension())
+    }
+
+    fn parse(s: &str) -> Result<Parent, Error> {
+        let parts: Vec<&str> = s.splitn(2, '.').collect();
+        if parts.len() != 2 {
+            bail!("invalid parent '{}'", s);
+        }
+
+        let timestamp = DateTime::parse_from_rfc3339(parts[0])?.with_timezone(&Utc);
+        let kind = TargetType::from_extension(parts[1])
+            .ok_or_else(|| format_err!("invalid parent type '{}'", parts[1]))?;
+
+        Ok(Parent::new(timestamp, kind))
+    }
+}
+
 pub struct Entry {
     path: PathBuf,
     modified: DateTime<Utc>,
     uid: u32,
     gid: u32,
     mode: u32,
+    size: Option<u64>,
+    offset: Option<u64>,
+    digest: Option<[u8; KEY_LENGTH]>,
+    stat: Option<Stat>,
+    link: Option<PathBuf>,
+}
+
+/// Metadata compared by `Manifest::contains_strict`.
+struct Stat {
+    size: u64,
+    ino: u64,
+    ctime: DateTime<Utc>,
 }
 
 impl Entry {
@@ -150,24 +488,68 @@ impl Entry {
             uid: uid,
             gid: gid,
             mode: mode,
+            size: None,
+            offset: None,
+            digest: None,
+            stat: None,
+            link: None,
         }
     }
 
+    /// Marks the entry as a hard link to an earlier entry of the archive.
+    pub fn set_link<P: AsRef<Path>>(&mut self, target: P) {
+        self.link = Some(target.as_ref().into());
+    }
+
+    pub fn set_stat<T>(&mut self, size: u64, ino: u64, ctime: T)
+        where T: Into<DateTime<Utc>>
+    {
+        self.stat = Some(Stat { size: size, ino: ino, ctime: ctime.into() });
+    }
+
+    pub fn set_offset(&mut self, offset: u64) {
+        self.offset = Some(offset);
+    }
+
+    pub fn set_content(&mut self, size: u64, digest: [u8; KEY_LENGTH]) {
+        self.size = Some(size);
+        self.digest = Some(digest);
+    }
+
     fn serialize<W>(&self, mut w: W) -> Result<(), Error>
         where W: Write
     {
         let path = self.path.to_str()
             .ok_or_else(|| format_err!("path is not valid utf-8"))?;
         bincode::serialize_into(&mut w, path.as_bytes())?;
+        self.serialize_meta(w)
+    }
+
+    fn serialize_meta<W>(&self, mut w: W) -> Result<(), Error>
+        where W: Write
+    {
         bincode::serialize_into(&mut w, &self.modified.timestamp())?;
         bincode::serialize_into(&mut w, &self.uid)?;
         bincode::serialize_into(&mut w, &self.gid)?;
         bincode::serialize_into(&mut w, &self.mode)?;
         Ok(())
     }
+
+    fn serialize_stat<W>(&self, mut w: W) -> Result<(), Error>
+        where W: Write
+    {
+        let stat = self.stat.as_ref()
+            .ok_or_else(|| format_err!("entry has no stat data"))?;
+        bincode::serialize_into(&mut w, &self.modified.timestamp_subsec_nanos())?;
+        bincode::serialize_into(&mut w, &stat.size)?;
+        bincode::serialize_into(&mut w, &stat.ino)?;
+        bincode::serialize_into(&mut w, &stat.ctime.timestamp())?;
+        bincode::serialize_into(&mut w, &stat.ctime.timestamp_subsec_nanos())?;
+        Ok(())
+    }
 }
 
-#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug)]
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone)]
 struct Key {
     data: [u8; KEY_LENGTH]
 }
@@ -178,20 +560,27 @@ impl From<[u8; KEY_LENGTH]> for Key {
     }
 }
 
+/// Manifest format. `Sha256` hashes all metadata into the key, so it can
+/// only answer whether an unchanged entry exists. `Sha256Records` keys
+/// entries by their path and stores a record for each.
+#[derive(Eq, PartialEq, Debug)]
 enum Algorithm {
-    Sha256
+    Sha256,
+    Sha256Records,
 }
 
 impl Algorithm {
     fn as_u32(&self) -> u32 {
         match self {
             Algorithm::Sha256 => 0x1,
+            Algorithm::Sha256Records => 0x2,
         }
     }
 
     fn from_u32(val: u32) -> Result<Algorithm, Error> {
         match val {
             0x1 => Ok(Algorithm::Sha256),
+            0x2 => Ok(Algorithm::Sha256Records),
             _ => Err(format_err!("invalid algorithm"))
         }
     }
@@ -201,7 +590,6 @@ impl Algorithm {
 mod test {
 
     use super::*;
-    use std::collections::BTreeSet;
 
 
     #[test]
@@ -209,15 +597,19 @@ mod test {
         let key = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
             16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31];
 
-        let mut keys = BTreeSet::new();
+        let mut records = BTreeMap::new();
         for i in 0..31 {
-            keys.insert({ let mut arr = key.clone(); arr.rotate_left(i); arr.into() });
+            records.insert({ let mut arr = key.clone(); arr.rotate_left(i); arr.into() }, Record::default());
 
         }
 
         let manifest = Manifest {
+            algorithm: Algorithm::Sha256,
             salt: key.into(),
-            keys: keys,
+            records: records,
+            deleted: BTreeMap::new(),
+            parent: None,
+            obfuscate: true,
         };
 
         let mut buffer = Vec::new();
@@ -232,14 +624,90 @@ mod test {
         assert_eq!(manifest, result);
     }
 
+    #[test]
+    fn round_trip_with_parent() {
+        use chrono::TimeZone;
+
+        let mut manifest = Manifest::new(true).unwrap();
+        let desc = TargetDescriptor::new("host", "job", Utc.timestamp(1500000000, 0), TargetType::Full);
+        manifest.set_parent(&desc);
+
+        let mut buffer = Vec::new();
+        manifest.serialize(&mut buffer).unwrap();
+
+        let result = Manifest::deserialize(&buffer[..]).unwrap();
+
+        assert_eq!(manifest, result);
+        assert!(result.parent().unwrap().matches(&desc));
+    }
+
+    #[test]
+    fn round_trip_records() {
+        use chrono::TimeZone;
+
+        let mut manifest = Manifest::new(false).unwrap();
+        let mut entry = Entry::new("foo/bar", Utc.timestamp(0, 0), 1000, 1000, 0o644);
+        entry.set_offset(1536);
+        entry.set_content(42, [7; KEY_LENGTH]);
+        manifest.insert(&entry);
+        manifest.insert(&Entry::new("foo", Utc.timestamp(0, 0), 1000, 1000, 0o755));
+
+        let mut buffer = Vec::new();
+        manifest.serialize(&mut buffer).unwrap();
+
+        let result = Manifest::deserialize(&buffer[..]).unwrap();
+
+        assert_eq!(manifest, result);
+
+        let record = result.find("foo/bar").unwrap();
+        assert_eq!(Some(42), record.size());
+        assert_eq!(Some(1536), record.offset());
+        assert_eq!(Some(&[7; KEY_LENGTH][..]), record.digest());
+        assert_eq!(Some(Path::new("foo/bar")), record.path());
+        assert_eq!(None, result.find("foo").unwrap().size());
+        assert!(result.find("baz").is_none());
+    }
+
+    #[test]
+    fn obfuscated_records_omit_path() {
+        use chrono::TimeZone;
+
+        let mut manifest = Manifest::new(true).unwrap();
+        manifest.insert(&Entry::new("secret/file", Utc.timestamp(0, 0), 0, 0, 0));
+
+        let mut buffer = Vec::new();
+        manifest.serialize(&mut buffer).unwrap();
+
+        let text = std::str::from_utf8(&buffer).unwrap();
+        assert!(!text.contains("path="));
+
+        let result = Manifest::deserialize(&buffer[..]).unwrap();
+        assert!(result.find("secret/file").unwrap().path().is_none());
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let salt = hex::encode([1; KEY_LENGTH]);
+        let key = hex::encode([2; KEY_LENGTH]);
+        let text = format!("2 {}\n{} size=5 color=blue\n", salt, key);
+
+        let result = Manifest::deserialize(text.as_bytes()).unwrap();
+
+        assert_eq!(1, result.len());
+    }
+
     #[test]
     fn test_contains() {
         let salt = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
             16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31];
 
         let mut manifest = Manifest {
+            algorithm: Algorithm::Sha256,
             salt: salt.into(),
-            keys: BTreeSet::new(),
+            records: BTreeMap::new(),
+            deleted: BTreeMap::new(),
+            parent: None,
+            obfuscate: true,
         };
 
         use chrono::TimeZone;
@@ -249,5 +717,129 @@ mod test {
 
         assert_eq!(true, manifest.contains(&entry))
     }
-}
 
+    #[test]
+    fn contains_compares_metadata() {
+        use chrono::TimeZone;
+
+        let mut manifest = Manifest::new(true).unwrap();
+        manifest.insert(&Entry::new("foo/bar", Utc.timestamp(0, 0), 0, 0, 0));
+
+        assert!(manifest.contains(&Entry::new("foo/bar", Utc.timestamp(0, 0), 0, 0, 0)));
+        assert!(!manifest.contains(&Entry::new("foo/bar", Utc.timestamp(1, 0), 0, 0, 0)));
+        assert!(!manifest.contains(&Entry::new("foo/baz", Utc.timestamp(0, 0), 0, 0, 0)));
+    }
+
+    #[test]
+    fn contains_strict_compares_stat() {
+        use chrono::TimeZone;
+
+        let entry = |nanos, size, ino| {
+            let mut e = Entry::new("foo/bar", Utc.timestamp(10, nanos), 0, 0, 0);
+            e.set_stat(size, ino, Utc.timestamp(20, 0));
+            e
+        };
+
+        let mut manifest = Manifest::new(true).unwrap();
+        manifest.insert(&entry(0, 5, 1));
+        manifest.insert(&Entry::new("foo/old", Utc.timestamp(10, 0), 0, 0, 0));
+
+        assert!(manifest.contains_strict(&entry(0, 5, 1)));
+        assert!(manifest.contains(&entry(500, 5, 1)));
+        assert!(!manifest.contains_strict(&entry(500, 5, 1)));
+        assert!(!manifest.contains_strict(&entry(0, 6, 1)));
+        assert!(!manifest.contains_strict(&entry(0, 5, 2)));
+
+        let mut old = Entry::new("foo/old", Utc.timestamp(10, 0), 0, 0, 0);
+        old.set_stat(0, 0, Utc.timestamp(0, 0));
+        assert!(manifest.contains(&old));
+        assert!(!manifest.contains_strict(&old));
+    }
+
+    #[test]
+    fn carry_keeps_content_without_offset() {
+        use chrono::TimeZone;
+
+        let mut base = Manifest::new(false).unwrap();
+        let mut entry = Entry::new("foo/bar", Utc.timestamp(0, 0), 0, 0, 0);
+        entry.set_offset(512);
+        entry.set_content(5, [3; KEY_LENGTH]);
+        base.insert(&entry);
+
+        let mut manifest = Manifest::new(false).unwrap();
+        manifest.inherit_salt(&base);
+        manifest.carry(&Entry::new("foo/bar", Utc.timestamp(0, 0), 0, 0, 0), &base);
+
+        let record = manifest.find("foo/bar").unwrap();
+        assert_eq!(Some(5), record.size());
+        assert_eq!(Some(&[3; KEY_LENGTH][..]), record.digest());
+        assert_eq!(None, record.offset());
+        assert!(manifest.contains(&entry));
+    }
+
+    #[test]
+    fn round_trip_deletions() {
+        use chrono::TimeZone;
+
+        let mut base = Manifest::new(true).unwrap();
+        base.insert(&Entry::new("foo/bar", Utc.timestamp(0, 0), 0, 0, 0));
+        base.insert(&Entry::new("foo/baz", Utc.timestamp(0, 0), 0, 0, 0));
+
+        let mut manifest = Manifest::new(true).unwrap();
+        manifest.inherit_salt(&base);
+        manifest.insert(&Entry::new("foo/bar", Utc.timestamp(1, 0), 0, 0, 0));
+        manifest.record_deletions(&base);
+
+        let mut buffer = Vec::new();
+        manifest.serialize(&mut buffer).unwrap();
+
+        let result = Manifest::deserialize(&buffer[..]).unwrap();
+
+        assert_eq!(manifest, result);
+        assert_eq!(1, result.len());
+        assert_eq!(1, result.deleted_len());
+        assert!(result.is_deleted("foo/baz"));
+        assert!(!result.is_deleted("foo/bar"));
+        assert!(result.find("foo/baz").is_none());
+    }
+
+    #[test]
+    fn round_trip_hard_link() {
+        use chrono::TimeZone;
+
+        let mut manifest = Manifest::new(true).unwrap();
+        manifest.insert(&Entry::new("foo/bar", Utc.timestamp(0, 0), 0, 0, 0));
+        let mut link = Entry::new("foo/baz", Utc.timestamp(0, 0), 0, 0, 0);
+        link.set_link("foo/bar");
+        manifest.insert(&link);
+
+        let mut buffer = Vec::new();
+        manifest.serialize(&mut buffer).unwrap();
+
+        let result = Manifest::deserialize(&buffer[..]).unwrap();
+
+        assert_eq!(manifest, result);
+        assert!(result.find("foo/bar").unwrap().link().is_none());
+        let target = result.salted_hash(b"foo/bar");
+        assert_eq!(Some(&target.data[..]), result.find("foo/baz").unwrap().link());
+    }
+
+    #[test]
+    fn test_content_matches() {
+        use chrono::TimeZone;
+
+        let mut manifest = Manifest::new(true).unwrap();
+        let mut hasher = manifest.content_hasher();
+        hasher.input(b"hello");
+        let mut digest = [0; KEY_LENGTH];
+        digest.copy_from_slice(&hasher.result()[..]);
+
+        let mut entry = Entry::new("foo/bar", Utc.timestamp(0, 0), 0, 0, 0);
+        entry.set_content(5, digest);
+        manifest.insert(&entry);
+
+        assert!(manifest.content_matches("foo/bar", &digest));
+        assert!(!manifest.content_matches("foo/bar", &[0; KEY_LENGTH]));
+        assert!(!manifest.content_matches("foo/baz", &digest));
+    }
+}
@@ -0,0 +1,224 @@
+use super::config;
+use super::backup::{Job, build_destination};
+use super::restore::resolve_parent;
+use super::destination::{BackupSearchRequest, TargetDescriptor, TargetType};
+
+use std::collections::BTreeSet;
+
+use anyhow::Error;
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+pub fn prune(job: &Job, host: &str, dry_run: bool) -> Result<(), Error> {
+    let policy = match job.retention {
+        Some(ref r) => r,
+        None => {
+            info!("job '{}' has no retention policy, nothing to prune", job.name);
+            return Ok(());
+        },
+    };
+
+    let destination = build_destination(&job.destination)?;
+
+    let request = BackupSearchRequest::new(host, job.name.as_str());
+    let mut backups = destination.list_backups(&request)?;
+    backups.sort_by(|a, b| a.timestamp().cmp(b.timestamp()));
+
+    debug!("resolving base backups");
+    let parents = backups.iter()
+        .map(|desc| match desc.kind() {
+            TargetType::Full => Ok(None),
+            _ => {
+                let parent = resolve_parent(job, destination.as_ref(), &backups, desc)?;
+                Ok(backups.iter().position(|b| b.timestamp() == parent.timestamp() && b.kind() == parent.kind()))
+            },
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let keep = select(&backups, &parents, policy, Utc::now());
+
+    // newest first, so a backup is always gone before the base it depends on
+    let mut removed = 0;
+    for (i, desc) in backups.iter().enumerate().rev() {
+        if keep.contains(&i) {
+            debug!("keeping backup from {}", desc.timestamp());
+            continue;
+        }
+
+        if dry_run {
+            info!("would delete backup from {}", desc.timestamp());
+        } else {
+            info!("deleting backup from {}", desc.timestamp());
+            destination.delete(desc)?;
+        }
+
+        removed += 1;
+    }
+
+    info!("kept {} backups, removed {}", keep.len(), removed);
+
+    Ok(())
+}
+
+/// Returns the indices of the backups to keep. `backups` must be sorted
+/// oldest first and `parents` holds the index of each backup's base.
+pub(crate) fn select(
+    backups: &[TargetDescriptor],
+    parents: &[Option<usize>],
+    policy: &config::Retention,
+    now: DateTime<Utc>)
+    -> BTreeSet<usize>
+{
+    let newest_first = (0..backups.len()).rev().collect::<Vec<_>>();
+    let mut keep = BTreeSet::new();
+
+    let has_rules = policy.keep_last_full.is_some()
+        || policy.keep_daily.is_some()
+        || policy.keep_weekly.is_some()
+        || policy.keep_monthly.is_some()
+        || policy.keep_yearly.is_some();
+
+    if !has_rules {
+        keep.extend(0..backups.len());
+    }
+
+    if let Some(count) = policy.keep_last_full {
+        keep.extend(newest_first.iter()
+            .filter(|&&i| backups[i].kind() == TargetType::Full)
+            .take(count));
+    }
+
+    keep_periods(&mut keep, backups, &newest_first, policy.keep_daily, |t| (t.year(), t.ordinal()));
+    keep_periods(&mut keep, backups, &newest_first, policy.keep_weekly, |t| {
+        let week = t.iso_week();
+        (week.year(), week.week())
+    });
+    keep_periods(&mut keep, backups, &newest_first, policy.keep_monthly, |t| (t.year(), t.month()));
+    keep_periods(&mut keep, backups, &newest_first, policy.keep_yearly, |t| (t.year(), 0));
+
+    if let Some(days) = policy.max_age_days {
+        let cutoff = now - Duration::days(days);
+        keep = keep.into_iter()
+            .filter(|&i| *backups[i].timestamp() >= cutoff)
+            .collect();
+    }
+
+    // never remove the latest backup, even if it is past the maximum age
+    if let Some(&latest) = newest_first.first() {
+        keep.insert(latest);
+    }
+
+    let selected = keep.iter().cloned().collect::<Vec<_>>();
+    for mut i in selected {
+        while let Some(parent) = parents[i] {
+            keep.insert(parent);
+            i = parent;
+        }
+    }
+
+    keep
+}
+
+fn keep_periods<F, K>(
+    keep: &mut BTreeSet<usize>,
+    backups: &[TargetDescriptor],
+    newest_first: &[usize],
+    count: Option<usize>,
+    period: F)
+    where F: Fn(&DateTime<Utc>) -> K, K: PartialEq
+{
+    let count = match count {
+        Some(c) => c,
+        None => return,
+    };
+
+    let mut last = None;
+    let mut found = 0;
+
+    for &i in newest_first {
+        if found >= count {
+            break;
+        }
+
+        let current = period(backups[i].timestamp());
+        if last.as_ref() != Some(&current) {
+            keep.insert(i);
+            last = Some(current);
+            found += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn backup(day: u32, hour: u32, kind: TargetType) -> TargetDescriptor {
+        TargetDescriptor::new("host", "job", Utc.ymd(2020, 1, day).and_hms(hour, 0, 0), kind)
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2020, 1, 31).and_hms(0, 0, 0)
+    }
+
+    #[test]
+    fn keep_everything_without_rules() {
+        let backups = vec![
+            backup(1, 0, TargetType::Full),
+            backup(2, 0, TargetType::Full),
+        ];
+        let keep = select(&backups, &[None, None], &config::Retention::default(), now());
+        assert_eq!(keep.len(), 2);
+    }
+
+    #[test]
+    fn keep_last_full() {
+        let backups = vec![
+            backup(1, 0, TargetType::Full),
+            backup(2, 0, TargetType::Full),
+            backup(3, 0, TargetType::Full),
+        ];
+        let policy = config::Retention { keep_last_full: Some(2), ..Default::default() };
+        let keep = select(&backups, &[None, None, None], &policy, now());
+        assert_eq!(keep.into_iter().collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn keep_newest_per_day() {
+        let backups = vec![
+            backup(1, 0, TargetType::Full),
+            backup(1, 12, TargetType::Full),
+            backup(2, 0, TargetType::Full),
+            backup(2, 12, TargetType::Full),
+        ];
+        let policy = config::Retention { keep_daily: Some(5), ..Default::default() };
+        let keep = select(&backups, &[None, None, None, None], &policy, now());
+        assert_eq!(keep.into_iter().collect::<Vec<_>>(), vec![1, 3]);
+    }
+
+    #[test]
+    fn keep_base_of_retained_differential() {
+        let backups = vec![
+            backup(1, 0, TargetType::Full),
+            backup(2, 0, TargetType::Differential),
+            backup(3, 0, TargetType::Differential),
+        ];
+        let policy = config::Retention { keep_daily: Some(1), ..Default::default() };
+        let keep = select(&backups, &[None, Some(0), Some(0)], &policy, now());
+        assert_eq!(keep.into_iter().collect::<Vec<_>>(), vec![0, 2]);
+    }
+
+    #[test]
+    fn remove_backups_past_max_age() {
+        let backups = vec![
+            backup(1, 0, TargetType::Full),
+            backup(22, 0, TargetType::Full),
+            backup(25, 0, TargetType::Full),
+        ];
+        let policy = config::Retention { max_age_days: Some(10), ..Default::default() };
+        let keep = select(&backups, &[None, None, None], &policy, now());
+        assert_eq!(keep.into_iter().collect::<Vec<_>>(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,606 @@
+use super::config;
+use super::backup::{Job, build_destination, fetch_manifest};
+use super::destination::{Destination, BackupSearchRequest, TargetDescriptor, TargetReader, TargetType};
+use super::encryption::{self, Decryptor, EncryptionKind};
+use super::compression::{self, Decompressor, CompressionKind};
+use super::manifest::Manifest;
+use super::source::Files;
+use super::sparse;
+use super::xattrs;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use tar;
+
+use anyhow::{Error, Context};
+
+use chrono::prelude::*;
+
+use nix::sys::stat::{self, UtimensatFlags};
+use nix::sys::time::{TimeSpec, TimeValLike};
+use nix::unistd::{self, FchownatFlags, Gid, Uid};
+
+pub fn restore(job: &Job, host: &str, timestamp: Option<DateTime<Utc>>, target: &Path) -> Result<(), Error> {
+    let destination = build_destination(&job.destination)?;
+
+    let request = BackupSearchRequest::new(host, job.name.as_str());
+    let mut backups = destination.list_backups(&request)?;
+    backups.sort_by(|a, b| a.timestamp().cmp(b.timestamp()));
+
+    let desc = match timestamp {
+        None => backups.last()
+            .ok_or_else(|| format_err!("no backups found for host = {}, job = {}", host, job.name))?,
+        Some(t) => backups.iter()
+            .find(|b| *b.timestamp() == t)
+            .ok_or_else(|| format_err!("no backup found for host = {}, job = {}, time = {}", host, job.name, t))?,
+    };
+
+    info!("restoring backup host = {}, job = {}, time = {}", desc.host(), desc.job(), desc.timestamp());
+
+    let chain = resolve_chain(job, destination.as_ref(), &backups, desc)?;
+
+    fs::create_dir_all(target)
+        .context(format!("failed to create restore target '{}'", target.display()))?;
+
+    let mut dirs = BTreeMap::new();
+
+    for (i, link) in chain.iter().enumerate() {
+        info!("applying backup {} of {}, time = {}", i + 1, chain.len(), link.timestamp());
+        let reader = if job.verification_key_file().is_some() {
+            open_verified(job, destination.as_ref(), link, target)
+                .context(format!("failed to verify backup from {}", link.timestamp()))?
+        } else {
+            destination.open(link)?
+        };
+        extract_archive(job, link, reader, target, &mut dirs)?;
+
+        if link.kind() != TargetType::Full {
+            let manifest = fetch_manifest(destination.as_ref(), link, Some(job))?;
+            apply_deletions(&manifest, target)?;
+        }
+    }
+
+    apply_dir_metadata(target, &dirs)?;
+
+    info!("restore completed successfully");
+
+    Ok(())
+}
+
+/// Walks the parent links recorded in the manifests back to the full backup
+/// and returns the chain in the order it has to be applied.
+pub(crate) fn resolve_chain(
+    job: &Job,
+    destination: &dyn Destination,
+    backups: &[TargetDescriptor],
+    desc: &TargetDescriptor)
+    -> Result<Vec<TargetDescriptor>, Error>
+{
+    let mut chain = vec![desc.clone()];
+
+    while chain[chain.len() - 1].kind() != TargetType::Full {
+        let parent = resolve_parent(job, destination, backups, &chain[chain.len() - 1])?;
+        chain.push(parent);
+    }
+
+    chain.reverse();
+
+    Ok(chain)
+}
+
+/// Finds the backup a differential was taken against. Older manifests do not
+/// record it, for those the last full backup before it is assumed.
+pub(crate) fn resolve_parent(
+    job: &Job,
+    destination: &dyn Destination,
+    backups: &[TargetDescriptor],
+    desc: &TargetDescriptor)
+    -> Result<TargetDescriptor, Error>
+{
+    let manifest = fetch_manifest(destination, desc, Some(job))?;
+
+    let parent = match manifest.parent() {
+        Some(p) => backups.iter()
+            .find(|b| p.matches(b))
+            .ok_or_else(|| format_err!("base backup of {} from {} not found", desc.timestamp(), p.timestamp()))?,
+        None => {
+            warn!("backup from {} does not record its base, using the last full backup before it", desc.timestamp());
+            backups.iter()
+                .filter(|b| b.kind() == TargetType::Full && b.timestamp() < desc.timestamp())
+                .max_by(|a, b| a.timestamp().cmp(b.timestamp()))
+                .ok_or_else(|| format_err!("no full backup found before {}", desc.timestamp()))?
+        },
+    };
+
+    debug!("backup from {} is based on {}", desc.timestamp(), parent.timestamp());
+
+    Ok(parent.clone())
+}
+
+/// Downloads a signed backup into an unnamed temp file in `dir` and reads it
+/// once without extracting it. The signature is only checked at the end of
+/// the stream, so this catches a bad signature before anything is written to
+/// the restore target. The returned reader extracts the same verified copy.
+fn open_verified(
+    job: &Job,
+    destination: &dyn Destination,
+    desc: &TargetDescriptor,
+    dir: &Path)
+    -> Result<Box<dyn TargetReader>, Error>
+{
+    info!("downloading backup from {}", desc.timestamp());
+    let mut reader = destination.open(desc)?;
+    let mut file = tempfile::tempfile_in(dir)?;
+    io::copy(&mut reader, &mut file)?;
+    reader.finalize()?;
+
+    info!("verifying signature of backup from {}", desc.timestamp());
+    file.seek(SeekFrom::Start(0))?;
+    let spool = Box::new(SpoolReader { file: file.try_clone()? });
+    let mut decompressor = create_read_pipeline(job, desc, spool)?;
+
+    io::copy(&mut decompressor, &mut io::sink())?;
+    decompressor.finalize()?.finalize()?.finalize()?;
+
+    file.seek(SeekFrom::Start(0))?;
+    Ok(Box::new(SpoolReader { file: file }))
+}
+
+/// Reads a backup downloaded by `open_verified`.
+struct SpoolReader {
+    file: fs::File,
+}
+
+impl io::Read for SpoolReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl TargetReader for SpoolReader {
+    fn finalize(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Metadata of a restored directory. Like `tar::Archive::unpack`, it is only
+/// applied once everything is extracted, so a read-only mode can't block
+/// extracting the entries inside and adding them doesn't change the mtime.
+struct DirMetadata {
+    header: tar::Header,
+    attrs: xattrs::XAttrs,
+}
+
+fn extract_archive(
+    job: &Job,
+    desc: &TargetDescriptor,
+    reader: Box<dyn TargetReader>,
+    target: &Path,
+    dirs: &mut BTreeMap<PathBuf, DirMetadata>)
+    -> Result<(), Error>
+{
+    info!("creating read pipeline");
+    let decompressor = create_read_pipeline(job, desc, reader)?;
+
+    let mut archive = tar::Archive::new(decompressor);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+
+    let restore_owner = unistd::geteuid().is_root();
+    if !restore_owner {
+        warn!("not running as root, file ownership will not be restored");
+    }
+
+    let mut count = 0;
+
+    debug!("extracting archive entries");
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let rel_path = entry.path()?.into_owned();
+        let attrs = match entry.pax_extensions()? {
+            Some(extensions) => xattrs::from_pax(extensions),
+            None => Vec::new(),
+        };
+        let sparse = match entry.pax_extensions()? {
+            Some(extensions) => sparse::from_pax(extensions)
+                .context(format!("failed to read sparse records of '{}'", rel_path.display()))?,
+            None => None,
+        };
+        let rel_path = match sparse {
+            Some(ref s) => s.name.clone(),
+            None => rel_path,
+        };
+
+        trace!("extracting '{}'", rel_path.display());
+        let unpacked = match sparse {
+            Some(ref s) => unpack_sparse(&mut entry, target, &rel_path, s.real_size),
+            None => entry.unpack_in(target).map_err(Error::from),
+        }.context(format!("failed to extract '{}'", rel_path.display()))?;
+
+        if !unpacked {
+            warn!("skipped unsafe path '{}'", rel_path.display());
+            continue;
+        }
+
+        if entry.header().entry_type().is_dir() {
+            fs::set_permissions(target.join(&rel_path), fs::Permissions::from_mode(0o700))
+                .context(format!("failed to make '{}' writable", rel_path.display()))?;
+            dirs.insert(rel_path, DirMetadata { header: entry.header().clone(), attrs: attrs });
+            count += 1;
+            continue;
+        }
+
+        if restore_owner {
+            set_owner(&target.join(&rel_path), entry.header())
+                .context(format!("failed to set owner of '{}'", rel_path.display()))?;
+        }
+
+        // applied after the owner, because changing it drops file capabilities
+        if !attrs.is_empty() {
+            xattrs::apply(&target.join(&rel_path), &attrs);
+        }
+
+        count += 1;
+    }
+
+    info!("extracted {} files", count);
+
+    let decompressor = archive.into_inner();
+    let decryptor = decompressor.finalize()?;
+    let reader = decryptor.finalize()?;
+    reader.finalize()?;
+
+    Ok(())
+}
+
+/// Extracts a PAX 1.0 sparse entry, which `tar::Entry::unpack_in` would write
+/// out raw under its placeholder name. Like `unpack_in`, it returns `false`
+/// for paths that would end up outside of `target`.
+fn unpack_sparse<R: io::Read>(entry: &mut tar::Entry<R>, target: &Path, rel_path: &Path, real_size: u64) -> Result<bool, Error> {
+    let safe = rel_path.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    });
+    if !safe {
+        return Ok(false);
+    }
+
+    let path = target.join(rel_path);
+    let parent = path.parent()
+        .ok_or_else(|| format_err!("path '{}' has no parent", path.display()))?;
+
+    // an earlier entry could have replaced a parent with a symlink, so like
+    // `unpack_in` the deepest existing ancestor is checked before anything is
+    // created below it
+    let mut missing = Vec::new();
+    let mut ancestor = parent;
+    while ancestor.symlink_metadata().is_err() {
+        missing.push(ancestor);
+        match ancestor.parent() {
+            Some(p) => ancestor = p,
+            None => break,
+        }
+    }
+
+    if !ancestor.canonicalize()?.starts_with(target.canonicalize()?) {
+        return Ok(false);
+    }
+
+    for dir in missing.into_iter().rev() {
+        fs::create_dir(dir)?;
+    }
+
+    match fs::remove_file(&path) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    sparse::unpack_pax_sparse(entry, &path, real_size)?;
+
+    fs::set_permissions(&path, fs::Permissions::from_mode(entry.header().mode()?))?;
+    let mtime = TimeSpec::seconds(entry.header().mtime()? as i64);
+    stat::utimensat(None, &path, &mtime, &mtime, UtimensatFlags::NoFollowSymlink)?;
+
+    Ok(true)
+}
+
+/// Removes the files a backup recorded as deleted since its base. Deleted
+/// entries are only known by their key, so every restored path is checked.
+fn apply_deletions(manifest: &Manifest, target: &Path) -> Result<(), Error> {
+    if manifest.deleted_len() == 0 {
+        return Ok(());
+    }
+
+    let mut deleted = Vec::new();
+    for entry in Files::new(target)? {
+        let (rel_path, _) = entry?;
+        if manifest.is_deleted(&rel_path) {
+            deleted.push(rel_path);
+        }
+    }
+
+    // parents sort before their children, so a removed directory takes its
+    // deleted children along
+    deleted.sort();
+
+    for rel_path in &deleted {
+        let path = target.join(rel_path);
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(m) => m,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        trace!("removing deleted '{}'", rel_path.display());
+        let result = if metadata.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        result.context(format!("failed to remove deleted '{}'", rel_path.display()))?;
+    }
+
+    info!("removed {} deleted files", deleted.len());
+
+    Ok(())
+}
+
+/// Applies the owner, mode, extended attributes and mtime of the restored
+/// directories, skipping the ones a later backup of the chain removed.
+fn apply_dir_metadata(target: &Path, dirs: &BTreeMap<PathBuf, DirMetadata>) -> Result<(), Error> {
+    let restore_owner = unistd::geteuid().is_root();
+
+    // children sort after their parents, so going backwards finishes every
+    // directory before its parent is made read-only
+    for (rel_path, dir) in dirs.iter().rev() {
+        let path = target.join(rel_path);
+        match fs::symlink_metadata(&path) {
+            Ok(ref m) if m.is_dir() => (),
+            Ok(_) => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        }
+
+        if restore_owner {
+            set_owner(&path, &dir.header)
+                .context(format!("failed to set owner of '{}'", rel_path.display()))?;
+        } else {
+            fs::set_permissions(&path, fs::Permissions::from_mode(dir.header.mode()?))
+                .context(format!("failed to set mode of '{}'", rel_path.display()))?;
+        }
+
+        if !dir.attrs.is_empty() {
+            xattrs::apply(&path, &dir.attrs);
+        }
+
+        let mtime = TimeSpec::seconds(dir.header.mtime()? as i64);
+        stat::utimensat(None, &path, &mtime, &mtime, UtimensatFlags::NoFollowSymlink)
+            .context(format!("failed to set mtime of '{}'", rel_path.display()))?;
+    }
+
+    debug!("applied metadata of {} directories", dirs.len());
+
+    Ok(())
+}
+
+fn set_owner(path: &Path, header: &tar::Header) -> Result<(), Error> {
+    let uid = Uid::from_raw(header.uid()? as u32);
+    let gid = Gid::from_raw(header.gid()? as u32);
+    unistd::fchownat(None, path, Some(uid), Some(gid), FchownatFlags::NoFollowSymlink)?;
+
+    // changing the owner clears the setuid and setgid bits, so the mode
+    // needs to be applied again afterwards
+    if !header.entry_type().is_symlink() {
+        let mode = header.mode()?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(())
+}
+
+/// Builds the read pipeline for a backup. The decoders are picked from the
+/// encoding recorded for the backup, the job only provides the keys. Backups
+/// without a recorded encoding are assumed to use the job's current one.
+pub(crate) fn create_read_pipeline(
+    job: &Job,
+    desc: &TargetDescriptor,
+    reader: Box<dyn TargetReader>)
+    -> Result<Box<dyn Decompressor>, Error>
+{
+    let encoding = match desc.encoding() {
+        Some(e) => *e,
+        None => {
+            debug!("backup from {} does not record its encoding, using the job's", desc.timestamp());
+            job.encoding()
+        },
+    };
+
+    // only PGP encrypted backups carry a signature
+    if job.verification_key_file().is_some() && encoding.encryption != Some(EncryptionKind::Pgp) {
+        bail!("backup from {} is not signed", desc.timestamp());
+    }
+
+    let decryptor = match encoding.encryption {
+        None => Box::new(encryption::identity::IdentityDecryptor::new(reader)) as Box<dyn Decryptor>,
+        Some(kind) => {
+            let cfg = job.encryption.as_ref()
+                .filter(|e| EncryptionKind::of(&e.typ) == kind)
+                .ok_or_else(|| format_err!("backup from {} is encrypted with {}, but job {} has no such encryption configured",
+                    desc.timestamp(), kind.extension(), job.name))?;
+
+            match cfg.typ {
+                config::EncryptionType::Pgp { ref privkey_file, .. } => {
+                    let key_file = privkey_file.as_ref()
+                        .ok_or_else(|| format_err!("encryption '{}' has no private key configured", cfg.name))?;
+                    let pgp = encryption::pgp::PgpDecryptor::new(reader, key_file, job.verification_key_file())?;
+                    Box::new(pgp) as Box<dyn Decryptor>
+                },
+                config::EncryptionType::Age { ref identity_file, .. } => {
+                    let identity_file = identity_file.as_ref()
+                        .ok_or_else(|| format_err!("encryption '{}' has no identity file configured", cfg.name))?;
+                    let age = encryption::age::AgeDecryptor::new(reader, identity_file)?;
+                    Box::new(age) as Box<dyn Decryptor>
+                },
+                config::EncryptionType::Symmetric { ref passphrase_file, ref key_file, max_work_factor } => {
+                    let secret = encryption::symmetric::Secret::load(
+                        passphrase_file.as_ref().map(|f| f.as_str()),
+                        key_file.as_ref().map(|f| f.as_str()))?;
+                    Box::new(encryption::symmetric::SymmetricDecryptor::new(reader, &secret, max_work_factor)?) as Box<dyn Decryptor>
+                },
+            }
+        },
+    };
+
+    let decompressor = match encoding.compression {
+        None => Box::new(compression::identity::IdentityDecompressor::new(decryptor)) as Box<dyn Decompressor>,
+        Some(CompressionKind::Gzip) => Box::new(compression::gzip::GzipDecompressor::new(decryptor)) as Box<dyn Decompressor>,
+        Some(CompressionKind::Zstd) => Box::new(compression::zstd::ZstdDecompressor::new(decryptor)?) as Box<dyn Decompressor>,
+        Some(CompressionKind::Xz) => Box::new(compression::xz::XzDecompressor::new(decryptor)) as Box<dyn Decompressor>,
+        Some(CompressionKind::Lz4) => Box::new(compression::lz4::Lz4Decompressor::new(decryptor)?) as Box<dyn Decompressor>,
+    };
+
+    Ok(decompressor)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    use std::io::{Read, Write};
+
+    use crate::backup::load_job;
+    use crate::destination::{Encoding, dir::DirectoryDestination, memory::MemoryReader};
+
+    use flate2::write::GzEncoder;
+
+    use sha2::Digest;
+
+    use tempfile::TempDir;
+
+    fn job(backups: &Path, signed: bool) -> Job {
+        let config = format!(r#"
+            [[jobs]]
+            name = "job"
+            type = "full"
+            source = "source"
+            destination = "backups"
+            {}
+
+            [[sources]]
+            name = "source"
+            type = "cephfs"
+            path = "/"
+
+            [[destinations]]
+            name = "backups"
+            type = "directory"
+            path = "{}"
+
+            [[encryption]]
+            name = "pgp"
+            type = "pgp"
+            pubkey_file = "pubkey.asc"
+            privkey_file = "privkey.asc"
+            verification_key_file = "verification.asc"
+        "#, if signed { "encryption = \"pgp\"" } else { "" }, backups.display());
+
+        let config: config::Config = toml::from_str(&config).unwrap();
+        load_job(&config, "job").unwrap()
+    }
+
+    fn compressed_only(kind: TargetType) -> TargetDescriptor {
+        TargetDescriptor::new("host", "job", Utc.timestamp(1500000000, 0), kind)
+            .with_encoding(Encoding { compression: Some(CompressionKind::Gzip), encryption: None })
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn reject_unsigned_archive() {
+        let dir = TempDir::new().unwrap();
+        let backups = dir.path().join("backups");
+        let destination = DirectoryDestination::new(&backups);
+        let desc = compressed_only(TargetType::Full);
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(6);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        builder.append_data(&mut header, "forged", &b"forged"[..]).unwrap();
+
+        let mut target = destination.allocate(&desc, 0).unwrap();
+        target.write_all(&gzip(&builder.into_inner().unwrap())).unwrap();
+        target.finalize().unwrap();
+        destination.upload_manifest(&desc, &gzip(b"")).unwrap();
+
+        let restored = dir.path().join("restored");
+        assert!(restore(&job(&backups, true), "host", None, &restored).is_err());
+        assert!(!restored.join("forged").exists());
+
+        restore(&job(&backups, false), "host", None, &restored).unwrap();
+        assert_eq!(fs::read(restored.join("forged")).unwrap(), b"forged");
+    }
+
+    #[test]
+    fn reject_unsigned_manifest() {
+        let dir = TempDir::new().unwrap();
+        let backups = dir.path().join("backups");
+        let destination = DirectoryDestination::new(&backups);
+        let desc = compressed_only(TargetType::Differential);
+
+        let mut data = Vec::new();
+        Manifest::new(false).unwrap().serialize(&mut data).unwrap();
+        destination.upload_manifest(&desc, &gzip(&data)).unwrap();
+
+        assert!(fetch_manifest(&destination, &desc, Some(&job(&backups, false))).is_ok());
+        assert!(fetch_manifest(&destination, &desc, Some(&job(&backups, true))).is_err());
+    }
+
+    #[test]
+    fn restore_large_sparse_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("image");
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        file.write_all(b"data").unwrap();
+        // a hole in front of the data would have to be hashed, which takes
+        // far too long for a test
+        let len = sparse::MAX_SPARSE_SIZE + 4096;
+        file.set_len(len).unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&file.metadata().unwrap());
+        let regions = sparse::data_regions(&file, len).unwrap().unwrap();
+        let mut reader = sparse::SparseReader::new(file, regions, len, sha2::Sha256::new());
+        sparse::append_sparse(&mut builder, &mut header, Path::new("disks/image"), &mut reader, Vec::new()).unwrap();
+        let data = builder.into_inner().unwrap();
+
+        let desc = TargetDescriptor::new("host", "job", Utc.timestamp(1500000000, 0), TargetType::Full)
+            .with_encoding(Encoding { compression: None, encryption: None });
+        let restored = dir.path().join("restored");
+        fs::create_dir(&restored).unwrap();
+        let mut dirs = BTreeMap::new();
+        extract_archive(&job(&dir.path().join("backups"), false), &desc, Box::new(MemoryReader::new(data)), &restored, &mut dirs).unwrap();
+
+        assert!(!restored.join("disks/GNUSparseFile.0").exists());
+        let mut file = fs::File::open(restored.join("disks/image")).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), len);
+        let mut start = [0; 4];
+        file.read_exact(&mut start).unwrap();
+        assert_eq!(&start, b"data");
+    }
+}
@@ -0,0 +1,240 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+/// What to do with a path found while walking a snapshot.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Decision {
+    /// Archive the path, and descend into it if it is a directory.
+    Keep,
+    /// Leave the path out, but still descend into it, because included
+    /// paths might be below it.
+    Skip,
+    /// Leave the path and everything below it out.
+    Prune,
+}
+
+/// Name of the tag file from the Cache Directory Tagging specification.
+const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+
+/// A valid `CACHEDIR.TAG` starts with exactly this header.
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Gitignore style `include` and `exclude` patterns, matched against paths
+/// relative to the snapshot root. Without include patterns everything is
+/// included. Excludes win over includes.
+///
+/// Directories can also be excluded by a marker file inside them, either a
+/// valid `CACHEDIR.TAG` or a file with one of the configured names.
+pub struct PathFilter {
+    include: Option<Gitignore>,
+    include_roots: Option<Vec<PathBuf>>,
+    exclude: Option<Gitignore>,
+    exclude_caches: bool,
+    markers: Vec<String>,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<PathFilter, Error> {
+        Ok(PathFilter {
+            include: build_patterns(include)?,
+            include_roots: include_roots(include),
+            exclude: build_patterns(exclude)?,
+            exclude_caches: false,
+            markers: Vec::new(),
+        })
+    }
+
+    /// Skips directories tagged with a valid `CACHEDIR.TAG`.
+    pub fn exclude_caches(mut self, exclude_caches: bool) -> PathFilter {
+        self.exclude_caches = exclude_caches;
+        self
+    }
+
+    /// Skips directories containing a file with one of these names.
+    pub fn exclude_markers(mut self, markers: &[String]) -> PathFilter {
+        self.markers = markers.to_vec();
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_none() && !self.exclude_caches && self.markers.is_empty()
+    }
+
+    /// Returns the name of the marker file that excludes the directory at
+    /// `path`, if there is one.
+    pub fn find_marker(&self, path: &Path) -> Result<Option<String>, Error> {
+        if self.exclude_caches && is_cache_dir(path)? {
+            return Ok(Some(CACHEDIR_TAG.to_string()));
+        }
+
+        for marker in &self.markers {
+            match fs::symlink_metadata(path.join(marker)) {
+                Ok(_) => return Ok(Some(marker.clone())),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn check(&self, rel_path: &Path, is_dir: bool) -> Decision {
+        if let Some(ref exclude) = self.exclude {
+            if exclude.matched(rel_path, is_dir).is_ignore() {
+                return if is_dir { Decision::Prune } else { Decision::Skip };
+            }
+        }
+
+        if let Some(ref include) = self.include {
+            if !include.matched_path_or_any_parents(rel_path, is_dir).is_ignore() {
+                if is_dir && !self.may_include_below(rel_path) {
+                    return Decision::Prune;
+                }
+                return Decision::Skip;
+            }
+        }
+
+        Decision::Keep
+    }
+
+    /// Checks if the directory at `rel_path` is above or below the literal
+    /// start of an include pattern, so included paths might be inside it.
+    fn may_include_below(&self, rel_path: &Path) -> bool {
+        match self.include_roots {
+            Some(ref roots) => roots.iter().any(|r| r.starts_with(rel_path) || rel_path.starts_with(r)),
+            None => true,
+        }
+    }
+}
+
+/// Returns the leading directories of the include patterns up to the first
+/// glob, or `None` if a pattern without a slash can match at any depth.
+fn include_roots(patterns: &[String]) -> Option<Vec<PathBuf>> {
+    let mut roots = Vec::new();
+
+    for pattern in patterns {
+        let pattern = pattern.trim_end().trim_end_matches('/');
+        if pattern.is_empty() || pattern.starts_with('#') || pattern.starts_with('!') {
+            continue;
+        }
+
+        if !pattern.contains('/') {
+            return None;
+        }
+
+        let root = pattern.trim_start_matches('/')
+            .split('/')
+            .take_while(|c| !c.contains(|ch| ch == '*' || ch == '?' || ch == '[' || ch == '\\'))
+            .collect::<PathBuf>();
+        roots.push(root);
+    }
+
+    Some(roots)
+}
+
+fn is_cache_dir(path: &Path) -> Result<bool, Error> {
+    let file = match fs::File::open(path.join(CACHEDIR_TAG)) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut header = Vec::with_capacity(CACHEDIR_SIGNATURE.len());
+    file.take(CACHEDIR_SIGNATURE.len() as u64).read_to_end(&mut header)?;
+
+    Ok(header == CACHEDIR_SIGNATURE)
+}
+
+fn build_patterns(patterns: &[String]) -> Result<Option<Gitignore>, Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GitignoreBuilder::new("");
+    for pattern in patterns {
+        builder.add_line(None, pattern)
+            .map_err(|e| format_err!("invalid pattern '{}': {}", pattern, e))?;
+    }
+
+    let patterns = builder.build()?;
+    Ok(Some(patterns))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn patterns(list: &[&str]) -> Vec<String> {
+        list.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn empty_filter_keeps_everything() {
+        let filter = PathFilter::new(&[], &[]).unwrap();
+        assert!(filter.is_empty());
+        assert_eq!(filter.check(Path::new("var/cache"), true), Decision::Keep);
+        assert_eq!(filter.check(Path::new("a.tmp"), false), Decision::Keep);
+    }
+
+    #[test]
+    fn excluded_dirs_are_pruned() {
+        let filter = PathFilter::new(&[], &patterns(&["/var/cache", "node_modules", "*.tmp"])).unwrap();
+        assert_eq!(filter.check(Path::new("var/cache"), true), Decision::Prune);
+        assert_eq!(filter.check(Path::new("var/lib"), true), Decision::Keep);
+        assert_eq!(filter.check(Path::new("src/app/node_modules"), true), Decision::Prune);
+        assert_eq!(filter.check(Path::new("home/a.tmp"), false), Decision::Skip);
+        assert_eq!(filter.check(Path::new("home/a.txt"), false), Decision::Keep);
+    }
+
+    #[test]
+    fn marker_files_exclude_dirs() {
+        use std::io::Write;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        for name in &["cache", "fake", "opt-out", "plain"] {
+            fs::create_dir(dir.path().join(name)).unwrap();
+        }
+        let mut tag = fs::File::create(dir.path().join("cache").join(CACHEDIR_TAG)).unwrap();
+        tag.write_all(b"Signature: 8a477f597d28d172789f06886806bc55\n# created by a tool").unwrap();
+        fs::write(dir.path().join("fake").join(CACHEDIR_TAG), b"Signature: nope").unwrap();
+        fs::write(dir.path().join("opt-out").join(".nobackup"), b"").unwrap();
+
+        let filter = PathFilter::new(&[], &[]).unwrap()
+            .exclude_caches(true)
+            .exclude_markers(&patterns(&[".nobackup"]));
+
+        assert!(!filter.is_empty());
+        assert_eq!(filter.find_marker(&dir.path().join("cache")).unwrap(), Some(CACHEDIR_TAG.to_string()));
+        assert_eq!(filter.find_marker(&dir.path().join("fake")).unwrap(), None);
+        assert_eq!(filter.find_marker(&dir.path().join("opt-out")).unwrap(), Some(".nobackup".to_string()));
+        assert_eq!(filter.find_marker(&dir.path().join("plain")).unwrap(), None);
+    }
+
+    #[test]
+    fn includes_select_subtrees() {
+        let filter = PathFilter::new(&patterns(&["/home", "/etc/*.conf"]), &patterns(&["*.tmp"])).unwrap();
+        assert_eq!(filter.check(Path::new("home"), true), Decision::Keep);
+        assert_eq!(filter.check(Path::new("home/user/file"), false), Decision::Keep);
+        assert_eq!(filter.check(Path::new("home/user/file.tmp"), false), Decision::Skip);
+        assert_eq!(filter.check(Path::new("etc"), true), Decision::Skip);
+        assert_eq!(filter.check(Path::new("etc/host.conf"), false), Decision::Keep);
+        assert_eq!(filter.check(Path::new("etc/passwd"), false), Decision::Skip);
+        assert_eq!(filter.check(Path::new("etc/ssl"), true), Decision::Skip);
+        assert_eq!(filter.check(Path::new("var"), true), Decision::Prune);
+        assert_eq!(filter.check(Path::new("usr/share"), true), Decision::Prune);
+    }
+
+    #[test]
+    fn unanchored_includes_walk_everything() {
+        let filter = PathFilter::new(&patterns(&["/home", "*.conf"]), &[]).unwrap();
+        assert_eq!(filter.check(Path::new("var"), true), Decision::Skip);
+        assert_eq!(filter.check(Path::new("var/app.conf"), false), Decision::Keep);
+        assert_eq!(filter.check(Path::new("var/app.log"), false), Decision::Skip);
+    }
+}
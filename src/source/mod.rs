@@ -1,14 +1,18 @@
 
 pub(crate) mod lvm;
 pub(crate) mod cephfs;
+pub(crate) mod filter;
 
 use std::fs::{self, Metadata, ReadDir};
 use std::mem;
+use std::os::unix::fs::MetadataExt;
 
 use std::path::{Path, PathBuf};
 
 use anyhow::Error;
 
+use self::filter::{Decision, PathFilter};
+
 pub trait Source {
     fn snapshot(&self) -> Result<Box<Snapshot>, Error>;
 }
@@ -22,19 +26,115 @@ pub trait Snapshot {
 pub struct Files<'a> {
     base: &'a Path,
     current: ReadDir,
-    stack: Vec<ReadDir>,
+    current_dev: u64,
+    stack: Vec<(ReadDir, u64)>,
+    filter: Option<PathFilter>,
+    one_file_system: bool,
+    include_mounts: Vec<PathBuf>,
+    skipped_files: usize,
+    skipped_dirs: usize,
 }
 
 impl<'a> Files<'a> {
-    fn new(base: &'a Path) -> Result<Files<'a>, Error> {
+    pub(crate) fn new(base: &'a Path) -> Result<Files<'a>, Error> {
         let start = fs::read_dir(base)?;
+        let dev = fs::metadata(base)?.dev();
         Ok(Files {
             base: base,
             current: start,
+            current_dev: dev,
             stack: Vec::new(),
+            filter: None,
+            one_file_system: false,
+            include_mounts: Vec::new(),
+            skipped_files: 0,
+            skipped_dirs: 0,
         })
     }
 
+    /// Leaves out the paths rejected by `filter`. Pruned directories are not
+    /// read at all.
+    pub fn with_filter(mut self, filter: PathFilter) -> Files<'a> {
+        if !filter.is_empty() {
+            self.filter = Some(filter);
+        }
+        self
+    }
+
+    /// Stops at mount boundaries, except for the mounts in `include_mounts`,
+    /// given relative to the base path.
+    pub fn one_file_system<P: AsRef<Path>>(mut self, include_mounts: &[P]) -> Files<'a> {
+        self.one_file_system = true;
+        self.include_mounts = include_mounts.iter()
+            .map(|p| p.as_ref().strip_prefix("/").unwrap_or(p.as_ref()).to_path_buf())
+            .collect();
+        self
+    }
+
+    /// Number of files and directories left out by the filter so far. A
+    /// pruned directory counts once, whatever is below it.
+    pub fn skipped(&self) -> (usize, usize) {
+        (self.skipped_files, self.skipped_dirs)
+    }
+
+    fn check(&mut self, path: &Path, is_dir: bool) -> Decision {
+        let filter = match self.filter {
+            Some(ref f) => f,
+            None => return Decision::Keep,
+        };
+
+        let decision = match path.strip_prefix(self.base) {
+            Ok(rel_path) => filter.check(rel_path, is_dir),
+            Err(_) => Decision::Keep,
+        };
+
+        let decision = match decision {
+            Decision::Keep | Decision::Skip if is_dir => match filter.find_marker(path) {
+                Ok(Some(marker)) => {
+                    info!("skipping directory '{}' because it contains '{}'", path.display(), marker);
+                    Decision::Prune
+                },
+                Ok(None) => decision,
+                Err(e) => {
+                    warn!("failed to check '{}' for marker files: {}", path.display(), e);
+                    decision
+                },
+            },
+            _ => decision,
+        };
+
+        if decision != Decision::Keep {
+            trace!("filter skipped '{}'", path.display());
+            if is_dir {
+                self.skipped_dirs += 1;
+            } else {
+                self.skipped_files += 1;
+            }
+        }
+
+        decision
+    }
+
+    /// Checks if the entry at `path` is on another file system than its
+    /// parent directory and has not been included explicitly.
+    fn is_foreign_mount(&self, path: &Path, metadata: &Metadata) -> bool {
+        if !self.one_file_system || metadata.dev() == self.current_dev {
+            return false;
+        }
+
+        let included = path.strip_prefix(self.base)
+            .map(|rel_path| self.include_mounts.iter().any(|m| m == rel_path))
+            .unwrap_or(false);
+
+        if included {
+            info!("descending into included mount '{}'", path.display());
+        } else {
+            info!("not crossing file system boundary at '{}'", path.display());
+        }
+
+        !included
+    }
+
     pub fn base_path(&self) -> &'a Path {
         self.base
     }
@@ -45,8 +145,9 @@ impl<'a> Files<'a> {
                 Some(Ok(e)) => e,
                 Some(Err(e)) => return Some(Err(e.into())),
                 None => match self.stack.pop() {
-                    Some(parent) => {
+                    Some((parent, dev)) => {
                         self.current = parent;
+                        self.current_dev = dev;
                         continue
                     },
                     None => return None,
@@ -59,19 +160,41 @@ impl<'a> Files<'a> {
             };
 
             let file_type = metadata.file_type();
+            let path = entry.path();
+
+            if self.is_foreign_mount(&path, &metadata) {
+                if file_type.is_dir() {
+                    self.skipped_dirs += 1;
+                } else {
+                    self.skipped_files += 1;
+                }
+                continue;
+            }
+
+            let decision = self.check(&path, file_type.is_dir());
 
             if file_type.is_file() || file_type.is_symlink() {
-                return Some(Ok((entry.path(), metadata)));
+                if decision != Decision::Keep {
+                    continue;
+                }
+                return Some(Ok((path, metadata)));
             }
 
             if file_type.is_dir() {
-                let child = match fs::read_dir(entry.path()) {
+                if decision == Decision::Prune {
+                    continue;
+                }
+                let child = match fs::read_dir(&path) {
                     Ok(list) => list,
                     Err(e) => return Some(Err(e.into())),
                 };
                 let parent = mem::replace(&mut self.current, child);
-                self.stack.push(parent);
-                return Some(Ok((entry.path(), metadata)));
+                let parent_dev = mem::replace(&mut self.current_dev, metadata.dev());
+                self.stack.push((parent, parent_dev));
+                if decision == Decision::Skip {
+                    continue;
+                }
+                return Some(Ok((path, metadata)));
             }
         }
     }
@@ -271,4 +394,36 @@ mod test {
         assert!(names.contains(&"foo"));
         assert!(names.contains(&"bar"));
     }
+
+    #[test]
+    fn list_with_filter() {
+        let dirs = vec![
+            ("keep/file", PathType::File),
+            ("keep/file.tmp", PathType::File),
+            ("cache/a", PathType::File),
+            ("cache/b/c", PathType::File),
+        ];
+        let dir = generate_fs_structure(dirs);
+        let filter = filter::PathFilter::new(&[], &["/cache".to_string(), "*.tmp".to_string()]).unwrap();
+        let mut files = Files::new(dir.path()).unwrap().with_filter(filter);
+        let entries = files.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        let names = get_relative_paths(&dir, &entries);
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&"keep"));
+        assert!(names.contains(&"keep/file"));
+        assert_eq!(files.skipped(), (1, 1));
+    }
+
+    #[test]
+    fn one_file_system_lists_same_device() {
+        let dirs = vec![
+            ("foo/bar", PathType::File),
+            ("baz", PathType::Directory),
+        ];
+        let dir = generate_fs_structure(dirs);
+        let mut files = Files::new(dir.path()).unwrap().one_file_system::<&str>(&[]);
+        let entries = files.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(files.skipped(), (0, 0));
+    }
 }
@@ -0,0 +1,470 @@
+use std::cmp;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+
+use nix::errno::Errno;
+use nix::unistd::{self, Whence};
+
+use sha2::Digest;
+
+use tar;
+
+use crate::xattrs;
+
+/// Data regions of a sparse entry have to be aligned to tar blocks.
+const BLOCK_SIZE: u64 = 512;
+
+/// Largest value the 11 octal digits of a GNU sparse map field can hold.
+/// The tar crate only reads octal sparse maps, so bigger files are stored in
+/// the PAX 1.0 sparse format instead.
+pub(crate) const MAX_SPARSE_SIZE: u64 = 0o77777777777;
+
+/// Longest decimal number in a PAX sparse map.
+const MAX_DIGITS: usize = 20;
+
+/// Number of sparse map entries in the GNU header and in each extension
+/// header following it.
+const HEADER_ENTRIES: usize = 4;
+const EXTENSION_ENTRIES: usize = 21;
+
+/// Finds the data regions of a file as `(offset, length)` pairs, widened to
+/// tar block boundaries. Returns `None` if the file has no holes or the file
+/// system can't report them.
+pub fn data_regions(file: &fs::File, len: u64) -> Result<Option<Vec<(u64, u64)>>, Error> {
+    let fd = file.as_raw_fd();
+    let mut regions: Vec<(u64, u64)> = Vec::new();
+    let mut pos = 0;
+
+    while pos < len {
+        let start = match unistd::lseek64(fd, pos as i64, Whence::SeekData) {
+            Ok(s) => s as u64,
+            // no data after pos, the rest of the file is a hole
+            Err(nix::Error::Sys(Errno::ENXIO)) => break,
+            Err(nix::Error::Sys(Errno::EINVAL)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let end = cmp::min(unistd::lseek64(fd, start as i64, Whence::SeekHole)? as u64, len);
+
+        let start = start / BLOCK_SIZE * BLOCK_SIZE;
+        let end = cmp::min((end + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE, len);
+
+        match regions.last_mut() {
+            Some(last) if last.0 + last.1 >= start => last.1 = cmp::max(last.0 + last.1, end) - last.0,
+            _ => regions.push((start, end - start)),
+        }
+
+        pos = end;
+    }
+
+    let data = regions.iter().map(|r| r.1).sum::<u64>();
+    if data == len {
+        return Ok(None);
+    }
+
+    Ok(Some(regions))
+}
+
+/// Reads the data regions of a sparse file, and hashes the whole file
+/// contents including the holes, so the digest matches the one of a file
+/// read in full.
+pub struct SparseReader<R> {
+    inner: R,
+    regions: Vec<(u64, u64)>,
+    real_size: u64,
+    index: usize,
+    remaining: u64,
+    position: u64,
+    hasher: sha2::Sha256,
+}
+
+impl<R: Read + Seek> SparseReader<R> {
+    pub fn new(inner: R, regions: Vec<(u64, u64)>, real_size: u64, hasher: sha2::Sha256) -> SparseReader<R> {
+        SparseReader {
+            inner: inner,
+            regions: regions,
+            real_size: real_size,
+            index: 0,
+            remaining: 0,
+            position: 0,
+            hasher: hasher,
+        }
+    }
+
+    /// Hashes the trailing hole and returns the file size and digest.
+    pub fn finish(mut self) -> (u64, [u8; 32]) {
+        let rest = self.real_size.saturating_sub(self.position);
+        self.hash_zeros(rest);
+
+        let mut digest = [0; 32];
+        digest.copy_from_slice(&self.hasher.result()[..]);
+        (self.real_size, digest)
+    }
+
+    fn hash_zeros(&mut self, count: u64) {
+        let zeros = [0; 64 * 1024];
+        let mut left = count;
+        while left > 0 {
+            let n = cmp::min(left, zeros.len() as u64) as usize;
+            self.hasher.input(&zeros[..n]);
+            left -= n as u64;
+        }
+        self.position += count;
+    }
+}
+
+impl<R: Read + Seek> Read for SparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            let (offset, len) = match self.regions.get(self.index) {
+                Some(&r) => r,
+                None => return Ok(0),
+            };
+            self.index += 1;
+            self.hash_zeros(offset - self.position);
+            self.inner.seek(SeekFrom::Start(offset))?;
+            self.remaining = len;
+        }
+
+        let max = cmp::min(buf.len() as u64, self.remaining) as usize;
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 && max > 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while reading"));
+        }
+
+        self.hasher.input(&buf[..n]);
+        self.position += n as u64;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Appends a sparse entry, preceded by a PAX header holding `pax` if it's
+/// not empty. `header` must have the file's metadata set, only the data
+/// regions of `reader` are stored.
+///
+/// Files up to `MAX_SPARSE_SIZE` are stored as old GNU sparse entries, which
+/// every tar implementation reads. Bigger ones use the PAX 1.0 format.
+pub fn append_sparse<W: Write, R: Read + Seek>(
+    builder: &mut tar::Builder<W>,
+    header: &mut tar::Header,
+    path: &Path,
+    reader: &mut SparseReader<R>,
+    pax: Vec<u8>)
+    -> io::Result<()>
+{
+    let mut map = reader.regions.clone();
+    // a trailing hole is described by an empty region at the end of the file
+    if map.last().map_or(true, |&(offset, len)| offset + len < reader.real_size) {
+        map.push((reader.real_size, 0));
+    }
+
+    if reader.real_size > MAX_SPARSE_SIZE {
+        return append_pax_sparse(builder, header, path, &map, reader, pax);
+    }
+
+    if !pax.is_empty() {
+        xattrs::append_pax_header(builder, &pax)?;
+    }
+
+    header.set_entry_type(tar::EntryType::GNUSparse);
+    header.set_size(reader.regions.iter().map(|r| r.1).sum());
+
+    let mut extensions = Vec::new();
+    {
+        let gnu = header.as_gnu_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "sparse entries need a GNU header"))?;
+        octal_into(&mut gnu.realsize, reader.real_size);
+
+        let (first, rest) = map.split_at(cmp::min(HEADER_ENTRIES, map.len()));
+        for (slot, &(offset, len)) in gnu.sparse.iter_mut().zip(first) {
+            octal_into(&mut slot.offset, offset);
+            octal_into(&mut slot.numbytes, len);
+        }
+        gnu.isextended[0] = if rest.is_empty() { 0 } else { 1 };
+
+        let chunks = rest.chunks(EXTENSION_ENTRIES).collect::<Vec<_>>();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut ext = tar::GnuExtSparseHeader::new();
+            for (slot, &(offset, len)) in ext.sparse.iter_mut().zip(chunk.iter()) {
+                octal_into(&mut slot.offset, offset);
+                octal_into(&mut slot.numbytes, len);
+            }
+            ext.isextended[0] = if i + 1 < chunks.len() { 1 } else { 0 };
+            extensions.extend_from_slice(ext.as_bytes());
+        }
+    }
+
+    // the extension headers sit between the header and the data, but don't
+    // count towards the entry size
+    builder.append_data(header, path, (&extensions[..]).chain(reader))
+}
+
+/// Appends a PAX 1.0 sparse entry. The PAX header holds the real name and
+/// size, the entry data starts with the sparse map in decimal, padded to a
+/// full block, followed by the data regions.
+fn append_pax_sparse<W: Write, R: Read + Seek>(
+    builder: &mut tar::Builder<W>,
+    header: &tar::Header,
+    path: &Path,
+    map: &[(u64, u64)],
+    reader: &mut SparseReader<R>,
+    mut pax: Vec<u8>)
+    -> io::Result<()>
+{
+    xattrs::write_pax_record(&mut pax, b"GNU.sparse.major", b"1");
+    xattrs::write_pax_record(&mut pax, b"GNU.sparse.minor", b"0");
+    xattrs::write_pax_record(&mut pax, b"GNU.sparse.name", path.as_os_str().as_bytes());
+    xattrs::write_pax_record(&mut pax, b"GNU.sparse.realsize", reader.real_size.to_string().as_bytes());
+    xattrs::append_pax_header(builder, &pax)?;
+
+    let mut data = format!("{}\n", map.len());
+    for &(offset, len) in map {
+        data.push_str(&format!("{}\n{}\n", offset, len));
+    }
+    let mut data = data.into_bytes();
+    let padded = (data.len() as u64 + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+    data.resize(padded as usize, 0);
+
+    // GNU tar only reads PAX sparse entries with a POSIX header
+    let mut ustar = tar::Header::new_ustar();
+    ustar.set_mode(header.mode()?);
+    ustar.set_uid(header.uid()?);
+    ustar.set_gid(header.gid()?);
+    ustar.set_mtime(header.mtime()?);
+    if let Ok(Some(name)) = header.username() {
+        ustar.set_username(name)?;
+    }
+    if let Ok(Some(name)) = header.groupname() {
+        ustar.set_groupname(name)?;
+    }
+    ustar.set_entry_type(tar::EntryType::Regular);
+    ustar.set_size(data.len() as u64 + reader.regions.iter().map(|r| r.1).sum::<u64>());
+
+    // tar implementations that don't know the format extract the raw entry
+    // under this name instead of overwriting the real file with it
+    let name = path.file_name().unwrap_or_else(|| path.as_os_str());
+    let raw_path = path.parent().unwrap_or_else(|| Path::new("")).join("GNUSparseFile.0").join(name);
+
+    builder.append_data(&mut ustar, raw_path, (&data[..]).chain(reader))
+}
+
+/// Real name and size of a PAX 1.0 sparse entry.
+pub struct PaxSparse {
+    pub name: PathBuf,
+    pub real_size: u64,
+}
+
+/// Reads the sparse file records from the PAX extensions of an entry.
+/// Returns `None` if the entry isn't a PAX sparse file.
+pub fn from_pax(extensions: tar::PaxExtensions) -> Result<Option<PaxSparse>, Error> {
+    let mut major = None;
+    let mut minor = None;
+    let mut name = None;
+    let mut real_size = None;
+
+    for extension in extensions {
+        let extension = extension?;
+        match extension.key_bytes() {
+            b"GNU.sparse.major" => major = Some(extension.value()?.to_string()),
+            b"GNU.sparse.minor" => minor = Some(extension.value()?.to_string()),
+            b"GNU.sparse.name" => name = Some(PathBuf::from(OsStr::from_bytes(extension.value_bytes()))),
+            b"GNU.sparse.realsize" => real_size = Some(extension.value()?.parse::<u64>()?),
+            _ => (),
+        }
+    }
+
+    match (major, minor) {
+        (None, None) => Ok(None),
+        (Some(ref major), Some(ref minor)) if major == "1" && minor == "0" => {
+            Ok(Some(PaxSparse {
+                name: name.ok_or_else(|| format_err!("PAX sparse entry has no name"))?,
+                real_size: real_size.ok_or_else(|| format_err!("PAX sparse entry has no size"))?,
+            }))
+        },
+        (major, minor) => bail!("unsupported PAX sparse format {}.{}", major.unwrap_or_default(), minor.unwrap_or_default()),
+    }
+}
+
+/// Writes the data of a PAX 1.0 sparse entry to a new file at `path`.
+pub fn unpack_pax_sparse<R: Read>(reader: &mut R, path: &Path, real_size: u64) -> Result<(), Error> {
+    let mut consumed = 0;
+    let count = read_decimal(reader, &mut consumed)?;
+
+    let mut map = Vec::new();
+    for _ in 0..count {
+        let offset = read_decimal(reader, &mut consumed)?;
+        let len = read_decimal(reader, &mut consumed)?;
+        if offset.checked_add(len).map_or(true, |end| end > real_size) {
+            bail!("sparse region {}+{} is beyond the end of the file", offset, len);
+        }
+        map.push((offset, len));
+    }
+
+    let padding = (BLOCK_SIZE - consumed % BLOCK_SIZE) % BLOCK_SIZE;
+    io::copy(&mut reader.by_ref().take(padding), &mut io::sink())?;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+
+    for (offset, len) in map {
+        file.seek(SeekFrom::Start(offset))?;
+        if io::copy(&mut reader.by_ref().take(len), &mut file)? != len {
+            bail!("sparse entry is truncated");
+        }
+    }
+
+    file.set_len(real_size)?;
+
+    Ok(())
+}
+
+/// Reads a newline terminated decimal number of a PAX sparse map.
+fn read_decimal<R: Read>(reader: &mut R, consumed: &mut u64) -> Result<u64, Error> {
+    let mut digits = String::new();
+    loop {
+        let mut byte = [0; 1];
+        reader.read_exact(&mut byte)?;
+        *consumed += 1;
+
+        match byte[0] {
+            b'\n' => break,
+            b @ b'0'..=b'9' if digits.len() < MAX_DIGITS => digits.push(b as char),
+            _ => bail!("invalid sparse map"),
+        }
+    }
+
+    Ok(digits.parse()?)
+}
+
+fn octal_into(dst: &mut [u8], val: u64) {
+    let digits = format!("{:0width$o}", val, width = dst.len() - 1);
+    dst[..digits.len()].copy_from_slice(digits.as_bytes());
+    dst[dst.len() - 1] = 0;
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn archive_sparse(contents: &[u8], regions: Vec<(u64, u64)>) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        let mut reader = SparseReader::new(Cursor::new(contents), regions, contents.len() as u64, sha2::Sha256::new());
+        append_sparse(&mut builder, &mut header, Path::new("image"), &mut reader, Vec::new()).unwrap();
+
+        let (_, digest) = reader.finish();
+        assert_eq!(&digest[..], &sha2::Sha256::digest(contents)[..]);
+
+        builder.into_inner().unwrap()
+    }
+
+    fn read_back(data: &[u8]) -> Vec<u8> {
+        let mut archive = tar::Archive::new(data);
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.header().entry_type(), tar::EntryType::GNUSparse);
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn round_trip_with_trailing_hole() {
+        let mut contents = vec![0; 8192];
+        for b in &mut contents[1024..1536] {
+            *b = 7;
+        }
+
+        let data = archive_sparse(&contents, vec![(1024, 512)]);
+
+        assert!(data.len() < contents.len());
+        assert_eq!(read_back(&data), contents);
+    }
+
+    #[test]
+    fn round_trip_with_extension_headers() {
+        let mut contents = vec![0; 64 * 1024];
+        let mut regions = Vec::new();
+        for i in 0..30 {
+            let offset = i * 2048;
+            for b in &mut contents[offset..offset + 512] {
+                *b = i as u8 + 1;
+            }
+            regions.push((offset as u64, 512));
+        }
+
+        let data = archive_sparse(&contents, regions);
+
+        assert_eq!(read_back(&data), contents);
+    }
+
+    #[test]
+    fn round_trip_pax_format() {
+        let mut contents = vec![0; 16 * 1024];
+        for b in &mut contents[4096..5120] {
+            *b = 3;
+        }
+        let regions = vec![(4096, 1024)];
+        let map = vec![(4096, 1024), (contents.len() as u64, 0)];
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        let mut reader = SparseReader::new(Cursor::new(&contents[..]), regions, contents.len() as u64, sha2::Sha256::new());
+        append_pax_sparse(&mut builder, &header, Path::new("disks/image"), &map, &mut reader, Vec::new()).unwrap();
+        let data = builder.into_inner().unwrap();
+
+        let mut archive = tar::Archive::new(&data[..]);
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), Path::new("disks/GNUSparseFile.0/image"));
+
+        let sparse = from_pax(entry.pax_extensions().unwrap().unwrap()).unwrap().unwrap();
+        assert_eq!(sparse.name, Path::new("disks/image"));
+        assert_eq!(sparse.real_size, contents.len() as u64);
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("image");
+        unpack_pax_sparse(&mut entry, &path, sparse.real_size).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), contents);
+    }
+
+    #[test]
+    fn detect_holes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("image");
+        let mut file = fs::File::create(&path).unwrap();
+        file.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        file.write_all(b"data").unwrap();
+        file.set_len(4 * 1024 * 1024).unwrap();
+        drop(file);
+
+        let file = fs::File::open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        match data_regions(&file, len).unwrap() {
+            // some file systems don't report holes
+            None => (),
+            Some(regions) => {
+                assert!(!regions.is_empty());
+                assert!(regions.iter().all(|r| r.0 % BLOCK_SIZE == 0));
+                assert!(regions[0].0 <= 1024 * 1024);
+                assert!(regions.iter().map(|r| r.1).sum::<u64>() < len);
+            },
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::{Error, Context};
+
+use nix::errno::Errno;
+
+use tar;
+
+/// Prefix of the PAX records holding extended attributes, as written by GNU
+/// tar and bsdtar.
+const PAX_PREFIX: &[u8] = b"SCHILY.xattr.";
+
+/// PAX records holding POSIX ACLs in text form, as written by star, GNU tar
+/// and bsdtar, keyed by the xattr Linux exposes the same ACL as.
+const PAX_ACLS: &[(&str, &[u8])] = &[
+    ("system.posix_acl_access", b"SCHILY.acl.access"),
+    ("system.posix_acl_default", b"SCHILY.acl.default"),
+];
+
+/// Version of the binary ACL format of the `system.posix_acl_*` xattrs.
+const ACL_EA_VERSION: u32 = 2;
+
+/// Extended attributes of a file, including SELinux labels, capabilities and
+/// POSIX ACLs, which Linux exposes as `security.*` and `system.posix_acl_*`.
+pub type XAttrs = Vec<(OsString, Vec<u8>)>;
+
+/// Reads all extended attributes of `path` without following symlinks. File
+/// systems without xattr support have none.
+pub fn read(path: &Path) -> Result<XAttrs, Error> {
+    let names = match xattr::list(path) {
+        Ok(n) => n,
+        Err(ref e) if is_unsupported(e) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut attrs = Vec::new();
+    for name in names {
+        let value = xattr::get(path, &name)
+            .context(format!("failed to read attribute {:?} of '{}'", name, path.display()))?;
+        if let Some(value) = value {
+            attrs.push((name, value));
+        }
+    }
+
+    Ok(attrs)
+}
+
+/// Sets extended attributes on `path`, logging the ones that can't be set,
+/// for example `security.*` attributes when not running as root.
+pub fn apply(path: &Path, attrs: &XAttrs) {
+    for (name, value) in attrs {
+        if let Err(e) = xattr::set(path, name, value) {
+            warn!("failed to set attribute {:?} on '{}': {}", name, path.display(), e);
+        }
+    }
+}
+
+/// Encodes `attrs` as PAX records. ACLs are also written as `SCHILY.acl.*`
+/// records, so other tar implementations can restore them. Restoring only
+/// uses the raw xattrs.
+pub fn pax_records(attrs: &XAttrs) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (name, value) in attrs {
+        let mut key = PAX_PREFIX.to_vec();
+        key.extend_from_slice(name.as_bytes());
+        write_pax_record(&mut data, &key, value);
+
+        if let Some(&(_, acl_key)) = PAX_ACLS.iter().find(|a| name == a.0) {
+            match acl_to_text(value) {
+                Some(text) => write_pax_record(&mut data, acl_key, text.as_bytes()),
+                None => warn!("failed to convert attribute {:?} to a text ACL", name),
+            }
+        }
+    }
+
+    data
+}
+
+/// Appends a PAX extended header holding `records`, which applies to the
+/// next entry of the archive. An entry can only have one.
+pub fn append_pax_header<W: Write>(builder: &mut tar::Builder<W>, records: &[u8]) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    let name = b"././@PaxHeader";
+    header.as_gnu_mut().unwrap().name[..name.len()].clone_from_slice(&name[..]);
+    header.set_mode(0o644);
+    header.set_entry_type(tar::EntryType::XHeader);
+    header.set_size(records.len() as u64);
+    header.set_cksum();
+
+    builder.append(&header, records)
+}
+
+/// Extracts the extended attributes from the PAX extensions of an entry.
+pub fn from_pax(extensions: tar::PaxExtensions) -> XAttrs {
+    extensions
+        .filter_map(|e| e.ok())
+        .filter(|e| e.key_bytes().starts_with(PAX_PREFIX))
+        .map(|e| (OsStr::from_bytes(&e.key_bytes()[PAX_PREFIX.len()..]).to_os_string(), e.value_bytes().to_vec()))
+        .collect()
+}
+
+/// Writes a `<length> <key>=<value>\n` record, where the length includes its
+/// own digits.
+pub fn write_pax_record(data: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len.to_string().len() + rest != len {
+        len = len.to_string().len() + rest;
+    }
+
+    data.extend_from_slice(len.to_string().as_bytes());
+    data.push(b' ');
+    data.extend_from_slice(key);
+    data.push(b'=');
+    data.extend_from_slice(value);
+    data.push(b'\n');
+}
+
+/// Converts an ACL in the binary xattr format to the short text form with
+/// numeric ids, like `user::rw-,user:1000:r--,group::r--,mask::r--,other::---`.
+fn acl_to_text(value: &[u8]) -> Option<String> {
+    if value.len() < 4 || (value.len() - 4) % 8 != 0 {
+        return None;
+    }
+
+    let mut version = [0; 4];
+    version.copy_from_slice(&value[..4]);
+    if u32::from_le_bytes(version) != ACL_EA_VERSION {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    for chunk in value[4..].chunks(8) {
+        let tag = u16::from_le_bytes([chunk[0], chunk[1]]);
+        let perm = u16::from_le_bytes([chunk[2], chunk[3]]);
+        let id = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+
+        let perms = [(4, 'r'), (2, 'w'), (1, 'x')].iter()
+            .map(|&(bit, c)| if perm & bit != 0 { c } else { '-' })
+            .collect::<String>();
+
+        let entry = match tag {
+            0x01 => format!("user::{}", perms),
+            0x02 => format!("user:{}:{}", id, perms),
+            0x04 => format!("group::{}", perms),
+            0x08 => format!("group:{}:{}", id, perms),
+            0x10 => format!("mask::{}", perms),
+            0x20 => format!("other::{}", perms),
+            _ => return None,
+        };
+        entries.push(entry);
+    }
+
+    Some(entries.join(","))
+}
+
+fn is_unsupported(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(Errno::EOPNOTSUPP as i32)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    use std::io::Read;
+
+    #[test]
+    fn pax_record_length() {
+        let mut data = Vec::new();
+        write_pax_record(&mut data, b"a", b"b");
+        assert_eq!(data, b"6 a=b\n");
+
+        let mut data = Vec::new();
+        write_pax_record(&mut data, b"key", &[b'x'; 92]);
+        assert_eq!(&data[..4], b"101 ");
+        assert_eq!(data.len(), 101);
+    }
+
+    fn acl_entry(data: &mut Vec<u8>, tag: u16, perm: u16, id: u32) {
+        data.extend_from_slice(&tag.to_le_bytes());
+        data.extend_from_slice(&perm.to_le_bytes());
+        data.extend_from_slice(&id.to_le_bytes());
+    }
+
+    #[test]
+    fn acl_text() {
+        let mut acl = ACL_EA_VERSION.to_le_bytes().to_vec();
+        acl_entry(&mut acl, 0x01, 6, u32::max_value());
+        acl_entry(&mut acl, 0x02, 4, 1000);
+        acl_entry(&mut acl, 0x04, 5, u32::max_value());
+        acl_entry(&mut acl, 0x10, 7, u32::max_value());
+        acl_entry(&mut acl, 0x20, 0, u32::max_value());
+
+        assert_eq!(acl_to_text(&acl).unwrap(), "user::rw-,user:1000:r--,group::r-x,mask::rwx,other::---");
+        assert_eq!(acl_to_text(&acl[..acl.len() - 1]), None);
+        assert_eq!(acl_to_text(&[1, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn round_trip_through_archive() {
+        let attrs = vec![
+            (OsString::from("user.comment"), b"hello".to_vec()),
+            (OsString::from("security.capability"), vec![0, 0, 0, 2, 0, 32, 0, 0]),
+        ];
+
+        let mut builder = tar::Builder::new(Vec::new());
+        append_pax_header(&mut builder, &pax_records(&attrs)).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "file", &b"data"[..]).unwrap();
+        let data = builder.into_inner().unwrap();
+
+        let mut archive = tar::Archive::new(&data[..]);
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap().to_str(), Some("file"));
+
+        let restored = from_pax(entry.pax_extensions().unwrap().unwrap());
+        assert_eq!(restored, attrs);
+
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"data");
+    }
+}